@@ -0,0 +1,391 @@
+use ::std::{
+    fs::File,
+    io, thread,
+    time::{Duration, Instant},
+};
+
+use crate::{retry::Backoff, LockBackend, RetryPolicy};
+
+#[cfg(target_os = "linux")]
+use crate::sys;
+
+/// Kind of lock to take on a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LockMode {
+    /// Lock that can be held by several owners at once as long as none holds it exclusive,
+    /// usually taken for reading.
+    Shared,
+    /// Lock that can only be held by a single owner at once, usually taken for writing.
+    Exclusive,
+}
+
+/// How a lock was acquired.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Acquisition {
+    /// By a single call waiting for the lock as long as needed.
+    Blocking,
+    /// By a single non-blocking attempt.
+    Try,
+    /// By repeated non-blocking attempts, as done by the timeout, deadline and retry bounded
+    /// constructors.
+    Polling,
+}
+
+/// How an acquisition waits for the lock.
+#[derive(Clone, Debug)]
+pub(crate) enum Wait {
+    /// With a single non-blocking attempt.
+    No,
+    /// With a single attempt waiting as long as needed, made again if interrupted by a signal.
+    Blocking,
+    /// With a single attempt waiting as long as needed, failing if interrupted by a signal.
+    Interruptible,
+    /// Polling following the policy, up to the deadline if any.
+    Retry(RetryPolicy, Option<Instant>),
+    /// With a single attempt waiting until the deadline, when it's interrupted by the signal,
+    /// made again if interrupted before and the flag is set.
+    #[cfg(target_os = "linux")]
+    Alarm(Instant, libc::c_int, bool),
+}
+
+impl Wait {
+    /// Polls with the default [`RetryPolicy`] until `deadline` is reached.
+    pub(crate) fn deadline(deadline: Instant) -> Self {
+        Self::Retry(RetryPolicy::default(), Some(deadline))
+    }
+
+    /// Like [`Wait::deadline`] but waiting at most `timeout`, blocking if the deadline can't be
+    /// represented.
+    pub(crate) fn timeout(timeout: Duration) -> Self {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => Self::deadline(deadline),
+            None => Self::Blocking,
+        }
+    }
+
+    /// Returns the acquisition waiting this way makes.
+    pub(crate) fn acquisition(&self) -> Acquisition {
+        match self {
+            Self::No => Acquisition::Try,
+            Self::Blocking | Self::Interruptible => Acquisition::Blocking,
+            Self::Retry(..) => Acquisition::Polling,
+            #[cfg(target_os = "linux")]
+            Self::Alarm(..) => Acquisition::Blocking,
+        }
+    }
+}
+
+/// Locks `f` with `backend` in `mode`, waiting for it as `wait` says.
+pub(crate) fn lock(
+    backend: &dyn LockBackend,
+    f: &File,
+    mode: LockMode,
+    wait: &Wait,
+) -> io::Result<()> {
+    match wait {
+        Wait::No => backend.try_lock(f, mode),
+        Wait::Blocking => lock_restarting(backend, f, mode),
+        Wait::Interruptible => backend.lock(f, mode),
+        Wait::Retry(policy, deadline) => {
+            lock_retry(backend, f, mode, &mut Retry::new(policy, *deadline))
+        }
+        #[cfg(target_os = "linux")]
+        Wait::Alarm(deadline, signal, restart) => {
+            let _alarm = sys::Alarm::new(*signal, *deadline)?;
+
+            loop {
+                match backend.lock(f, mode) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                        if Instant::now() >= *deadline {
+                            return Err(timed_out());
+                        } else if !restart {
+                            return Err(e);
+                        }
+                    }
+                    r => return r,
+                }
+            }
+        }
+    }
+}
+
+/// Calls [`LockBackend::lock`] until it isn't interrupted by a signal.
+fn lock_restarting(backend: &dyn LockBackend, f: &File, mode: LockMode) -> io::Result<()> {
+    loop {
+        match backend.lock(f, mode) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            r => return r,
+        }
+    }
+}
+
+/// Error a conversion failed with.
+#[derive(Debug)]
+pub(crate) struct ConversionError {
+    pub(crate) error: io::Error,
+    /// Whether the lock was released and couldn't be taken again.
+    pub(crate) lost: bool,
+}
+
+impl ConversionError {
+    pub(crate) fn kept(error: io::Error) -> Self {
+        Self { error, lost: false }
+    }
+
+    fn lost(error: io::Error) -> Self {
+        Self { error, lost: true }
+    }
+}
+
+/// Converts the lock held on `f` with `backend` to `to`, waiting for it if `wait` is set and
+/// again if interrupted by a signal if `restart` is, and returns whether the conversion was
+/// atomic.
+///
+/// If a non-atomic non-blocking conversion fails because of contention the lock is taken again
+/// in `from` mode if that can be done without waiting, and the contention error is returned
+/// either way along with whether the lock was lost.
+pub(crate) fn convert(
+    backend: &dyn LockBackend,
+    f: &File,
+    from: LockMode,
+    to: LockMode,
+    wait: bool,
+    restart: bool,
+) -> Result<bool, ConversionError> {
+    let blocking = if restart {
+        Wait::Blocking
+    } else {
+        Wait::Interruptible
+    };
+
+    if backend.converts_atomically() {
+        if wait {
+            lock(backend, f, to, &blocking).map_err(ConversionError::kept)?;
+        } else {
+            backend.try_lock(f, to).map_err(ConversionError::kept)?;
+        }
+
+        return Ok(true);
+    }
+
+    // `LockFileEx` can't convert locks, so the current one has to be released first.
+    #[cfg(windows)]
+    backend.unlock(f).map_err(ConversionError::kept)?;
+
+    if wait {
+        lock(backend, f, to, &blocking).map_err(ConversionError::lost)?;
+    } else if let Err(error) = backend.try_lock(f, to) {
+        // `flock` releases the current lock before trying the new one, even if it then fails, and
+        // someone else may have taken it meanwhile.
+        let lost = if is_contended(&error) {
+            backend.try_lock(f, from).is_err()
+        } else {
+            cfg!(windows)
+        };
+
+        return Err(ConversionError { error, lost });
+    }
+
+    // The current lock is released before taking the new one in both `flock` and `LockFileEx`.
+    Ok(false)
+}
+
+/// Returns whether `e` is the error a non-blocking attempt fails with when the lock is held
+/// elsewhere.
+pub(crate) fn is_contended(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+        || e.raw_os_error().is_some()
+            && e.raw_os_error() == fs2::lock_contended_error().raw_os_error()
+}
+
+pub(crate) fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for file lock")
+}
+
+/// Polls [`LockBackend::try_lock`] until it succeeds, fails for a reason other than contention or
+/// `retry` gives up.
+pub(crate) fn lock_retry(
+    backend: &dyn LockBackend,
+    f: &File,
+    mode: LockMode,
+    retry: &mut Retry<'_>,
+) -> io::Result<()> {
+    loop {
+        match backend.try_lock(f, mode) {
+            Err(e) if is_contended(&e) => thread::sleep(retry.failed(e)?),
+            r => return r,
+        }
+    }
+}
+
+/// Bookkeeping of a polling acquisition following a [`RetryPolicy`].
+pub(crate) struct Retry<'a> {
+    backoff: Backoff<'a>,
+    attempts: u32,
+    max_attempts: Option<u32>,
+    deadline: Option<Instant>,
+}
+
+impl<'a> Retry<'a> {
+    /// Starts an acquisition giving up when `policy` says so or when `deadline` is reached,
+    /// whichever comes first.
+    pub(crate) fn new(policy: &'a RetryPolicy, deadline: Option<Instant>) -> Self {
+        let elapsed = policy.elapsed().and_then(|d| Instant::now().checked_add(d));
+
+        Self {
+            backoff: policy.backoff(),
+            attempts: 0,
+            max_attempts: policy.attempts(),
+            deadline: match (elapsed, deadline) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+
+    /// Records an attempt that failed with the contention error `e`, returning how long to wait
+    /// before the next one or the error to give up with: `e` itself if there are no attempts
+    /// left or one of kind [`io::ErrorKind::TimedOut`] if the deadline was reached.
+    pub(crate) fn failed(&mut self, e: io::Error) -> io::Result<Duration> {
+        self.attempts += 1;
+
+        if self.max_attempts.is_some_and(|max| self.attempts >= max) {
+            return Err(e);
+        }
+
+        let wait = self.backoff.next_wait();
+
+        match self.deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+
+                if now >= deadline {
+                    Err(timed_out())
+                } else {
+                    Ok(wait.min(deadline - now))
+                }
+            }
+            None => Ok(wait),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{open_lock_file, Exclusive, LockOptions, OwnedFileLock, Shared};
+    use std::{env, fs, path::PathBuf, process};
+
+    /// File removed at dropping.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let name = format!("raii_flock-convert-{}-{}", name, process::id());
+            Self(env::temp_dir().join(name))
+        }
+
+        fn open(&self) -> File {
+            open_lock_file(&self.0, 0o600).unwrap()
+        }
+
+        fn can_lock(&self, mode: LockMode) -> bool {
+            let f = self.open();
+            let r = match mode {
+                LockMode::Shared => OwnedFileLock::<_, Shared>::try_wrap_shared(f).map(drop),
+                LockMode::Exclusive => {
+                    OwnedFileLock::<_, Exclusive>::try_wrap_exclusive(f).map(drop)
+                }
+            };
+            r.is_ok()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn contended_try_upgrade_keeps_shared_lock() {
+        let file = TempFile::new("contended");
+        let guard = OwnedFileLock::try_wrap_shared(file.open()).unwrap();
+        let other = OwnedFileLock::try_wrap_shared(file.open()).unwrap();
+
+        let (guard, e) = guard.try_upgrade().unwrap_err().into_parts();
+        assert!(is_contended(&e));
+        assert!(!guard.lost());
+        assert_eq!(guard.mode(), LockMode::Shared);
+
+        drop(other);
+        assert!(file.can_lock(LockMode::Shared));
+        assert!(!file.can_lock(LockMode::Exclusive));
+        drop(guard);
+        assert!(file.can_lock(LockMode::Exclusive));
+    }
+
+    #[test]
+    fn flock_conversions_arent_atomic() {
+        let file = TempFile::new("flock");
+        let guard = OwnedFileLock::try_wrap_shared(file.open()).unwrap();
+
+        let upgraded = guard.try_upgrade().unwrap();
+        assert!(!upgraded.atomic);
+        assert!(!upgraded.guard.downgrade().unwrap().atomic);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn ofd_conversions_are_atomic() {
+        let file = TempFile::new("ofd");
+        let options = LockOptions::new().backend(&crate::OfdFcntl);
+        let guard: OwnedFileLock<_, Shared> = options.try_wrap(file.open()).unwrap();
+
+        let upgraded = guard.try_upgrade().unwrap();
+        assert!(upgraded.atomic);
+        assert!(upgraded.guard.downgrade().unwrap().atomic);
+    }
+
+    /// Backend whose blocking locks always succeed and non-blocking ones never do.
+    #[derive(Debug)]
+    struct AlwaysContended;
+
+    impl LockBackend for AlwaysContended {
+        fn lock(&self, _: &File, _: LockMode) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn try_lock(&self, _: &File, _: LockMode) -> io::Result<()> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        fn unlock(&self, _: &File) -> io::Result<()> {
+            panic!("lost lock unlocked")
+        }
+    }
+
+    #[test]
+    fn try_upgrade_reports_lock_not_taken_again() {
+        let file = TempFile::new("lost");
+        let options = LockOptions::new().backend(&AlwaysContended);
+        let guard: OwnedFileLock<_, Shared> = options.wrap(file.open()).unwrap();
+
+        let (guard, e) = guard.try_upgrade().unwrap_err().into_parts();
+        assert!(is_contended(&e));
+        assert!(guard.lost());
+        drop(guard);
+    }
+
+    #[test]
+    fn downgrade_lets_shared_locks_in() {
+        let file = TempFile::new("downgrade");
+        let guard = OwnedFileLock::try_wrap_exclusive(file.open()).unwrap();
+        assert!(!file.can_lock(LockMode::Shared));
+
+        let guard = guard.downgrade().unwrap().guard;
+        assert!(file.can_lock(LockMode::Shared));
+        assert!(!file.can_lock(LockMode::Exclusive));
+        drop(guard);
+    }
+}
@@ -0,0 +1,282 @@
+use ::{
+    std::{
+        fs::{File as StdFile, Metadata},
+        io::{self, SeekFrom},
+        marker::PhantomData,
+        mem::ManuallyDrop,
+        ops::Deref,
+        pin::Pin,
+        ptr,
+        task::{Context, Poll},
+        time::{Duration, Instant},
+    },
+    tokio::{
+        fs::File,
+        io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf},
+    },
+};
+
+use crate::{
+    acquire::{self, LockMode, Retry, Wait},
+    Acquisition, DropPolicy, Exclusive, LockError, LockOptions, LockState, Mode, RetryPolicy,
+    Shared,
+};
+
+#[cfg(doc)]
+use fs2::FileExt;
+
+/// Asynchronous counterpart of [`OwnedFileLock`][`crate::OwnedFileLock`] over a
+/// [`tokio::fs::File`], calling [`FileExt::unlock`] at [dropping][`Drop`].
+///
+/// Waiting for the lock is done by polling [`FileExt::try_lock_shared`] or
+/// [`FileExt::try_lock_exclusive`] with [`tokio::time::sleep`] in between following the default
+/// [`RetryPolicy`], so no worker thread is ever blocked and dropping the future stops waiting
+/// without leaving the file locked.
+///
+/// As [`tokio::fs::File`] finishes writes in the background, the guard should be
+/// [flushed][`AsyncWrite::poll_flush`] before dropping it for them to happen while the
+/// file is still locked. `M` is either [`Shared`] or [`Exclusive`], only the latter implementing
+/// [`AsyncWrite`] and giving access to the file.
+#[derive(Debug)]
+pub struct AsyncFileLock<M: Mode> {
+    file: File,
+    state: LockState,
+    mode: PhantomData<M>,
+}
+
+impl<M: Mode> AsyncFileLock<M> {
+    fn new(file: File, state: LockState) -> Self {
+        Self {
+            file,
+            state,
+            mode: PhantomData,
+        }
+    }
+
+    pub(crate) fn try_wrap_with(f: File, options: &LockOptions) -> io::Result<Self> {
+        let state = with_std(&f, |std| options.lock(std, M::MODE, Wait::No))?;
+        Ok(Self::new(f, state))
+    }
+
+    pub(crate) async fn wrap_with(
+        f: File,
+        options: &LockOptions,
+        deadline: Option<Instant>,
+    ) -> io::Result<Self> {
+        let state = lock(options, &f, M::MODE, deadline).await?;
+        Ok(Self::new(f, state))
+    }
+
+    /// Returns the mode the lock is held in, which is always `M::MODE`.
+    #[inline(always)]
+    pub fn mode(&self) -> LockMode {
+        self.state.mode
+    }
+
+    /// Returns how the lock was acquired.
+    #[inline(always)]
+    pub fn acquisition(&self) -> Acquisition {
+        self.state.acquisition
+    }
+
+    /// Returns when the lock was acquired.
+    #[inline(always)]
+    pub fn acquired_at(&self) -> Instant {
+        self.state.acquired_at
+    }
+
+    /// Sets the policy followed if unlocking fails at [dropping][`Drop`], overriding the global
+    /// one.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.state.policy = Some(policy);
+    }
+
+    /// Queries metadata about the underlying file with [`File::metadata`].
+    pub async fn metadata(&self) -> io::Result<Metadata> {
+        self.file.metadata().await
+    }
+
+    fn into_parts(self) -> (File, LockState) {
+        let this = ManuallyDrop::new(self);
+        // SAFETY: `this` is never dropped nor used again after moving its fields out.
+        unsafe { (ptr::read(&this.file), ptr::read(&this.state)) }
+    }
+
+    /// Unlocks the file the same way [dropping][`Drop`] does and gives it back.
+    pub fn into_inner(self) -> File {
+        let (f, state) = self.into_parts();
+
+        with_std(&f, |std| {
+            if let Err(e) = state.unlock(std) {
+                state.report(e, std);
+            }
+        });
+
+        f
+    }
+
+    /// Unlocks the file and gives it back, returning it along with the error instead of reporting
+    /// it like [dropping][`Drop`] does if unlocking fails.
+    pub fn try_into_inner(self) -> Result<File, LockError<File>> {
+        let (f, state) = self.into_parts();
+
+        match with_std(&f, |std| state.unlock(std)) {
+            Ok(()) => Ok(f),
+            Err(e) => Err(LockError::new(f, e)),
+        }
+    }
+
+    /// Unlocks the file returning any error that could have caused instead of reporting it like
+    /// [dropping][`Drop`] does.
+    pub fn unlock(self) -> io::Result<()> {
+        self.try_into_inner().map(drop).map_err(io::Error::from)
+    }
+}
+
+impl AsyncFileLock<Shared> {
+    /// Creates a `Self` instance calling [`FileExt::try_lock_shared`] on `f` and returning any
+    /// error that could have caused.
+    pub fn try_wrap_shared(f: File) -> io::Result<Self> {
+        Self::try_wrap_with(f, &LockOptions::new())
+    }
+
+    /// Creates a `Self` instance waiting until `f` can be locked shared and returning any error
+    /// that could have caused.
+    pub async fn wrap_shared(f: File) -> io::Result<Self> {
+        Self::wrap_with(f, &LockOptions::new(), None).await
+    }
+
+    /// Creates a `Self` instance waiting until `f` can be locked shared or `timeout` elapses,
+    /// returning an error of kind [`io::ErrorKind::TimedOut`] in the latter case or any other
+    /// error that could have caused.
+    pub async fn wrap_shared_timeout(f: File, timeout: Duration) -> io::Result<Self> {
+        Self::wrap_with(f, &LockOptions::new(), Instant::now().checked_add(timeout)).await
+    }
+}
+
+impl AsyncFileLock<Exclusive> {
+    /// Creates a `Self` instance calling [`FileExt::try_lock_exclusive`] on `f` and returning
+    /// any error that could have caused.
+    pub fn try_wrap_exclusive(f: File) -> io::Result<Self> {
+        Self::try_wrap_with(f, &LockOptions::new())
+    }
+
+    /// Creates a `Self` instance waiting until `f` can be locked exclusive and returning any
+    /// error that could have caused.
+    pub async fn wrap_exclusive(f: File) -> io::Result<Self> {
+        Self::wrap_with(f, &LockOptions::new(), None).await
+    }
+
+    /// Creates a `Self` instance waiting until `f` can be locked exclusive or `timeout`
+    /// elapses, returning an error of kind [`io::ErrorKind::TimedOut`] in the latter case or any
+    /// other error that could have caused.
+    pub async fn wrap_exclusive_timeout(f: File, timeout: Duration) -> io::Result<Self> {
+        Self::wrap_with(f, &LockOptions::new(), Instant::now().checked_add(timeout)).await
+    }
+
+    /// Returns a reference to the wrapped file.
+    #[inline(always)]
+    pub fn get_ref(&self) -> &File {
+        &self.file
+    }
+}
+
+async fn lock(
+    options: &LockOptions,
+    f: &File,
+    mode: LockMode,
+    deadline: Option<Instant>,
+) -> io::Result<LockState> {
+    let policy = RetryPolicy::default();
+    let mut retry = Retry::new(&policy, deadline);
+
+    loop {
+        match with_std(f, |std| options.lock(std, mode, Wait::No)) {
+            Err(e) if acquire::is_contended(&e) => tokio::time::sleep(retry.failed(e)?).await,
+            r => return r.map(|state| state.polled()),
+        }
+    }
+}
+
+/// Calls `op` with a [`std::fs::File`] sharing the handle of `f` without taking ownership of it.
+fn with_std<R>(f: &File, op: impl FnOnce(&StdFile) -> R) -> R {
+    #[cfg(unix)]
+    let std = {
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+        // SAFETY: the handle outlives `std`, which never closes it.
+        ManuallyDrop::new(unsafe { StdFile::from_raw_fd(f.as_raw_fd()) })
+    };
+    #[cfg(windows)]
+    let std = {
+        use std::os::windows::io::{AsRawHandle, FromRawHandle};
+        // SAFETY: the handle outlives `std`, which never closes it.
+        ManuallyDrop::new(unsafe { StdFile::from_raw_handle(f.as_raw_handle()) })
+    };
+
+    op(&std)
+}
+
+impl<M: Mode> AsyncRead for AsyncFileLock<M> {
+    #[inline(always)]
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncFileLock<Exclusive> {
+    #[inline(always)]
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.file).poll_write(cx, buf)
+    }
+
+    #[inline(always)]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    #[inline(always)]
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_shutdown(cx)
+    }
+}
+
+impl<M: Mode> AsyncSeek for AsyncFileLock<M> {
+    #[inline(always)]
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.file).start_seek(position)
+    }
+
+    #[inline(always)]
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.file).poll_complete(cx)
+    }
+}
+
+impl Deref for AsyncFileLock<Exclusive> {
+    type Target = File;
+
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        &self.file
+    }
+}
+
+impl<M: Mode> Drop for AsyncFileLock<M> {
+    fn drop(&mut self) {
+        let state = &self.state;
+
+        with_std(&self.file, |std| {
+            if let Err(e) = state.unlock(std) {
+                state.report(e, std);
+            }
+        });
+    }
+}
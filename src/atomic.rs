@@ -0,0 +1,188 @@
+use ::std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, prelude::*},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{file_id::FileId, open_lock_file, Exclusive, OwnedFileLock, LOCK_FILE_MODE};
+
+/// Writer replacing the whole contents of a file atomically while holding it locked exclusive.
+///
+/// The new contents are written to a temporary file in the same directory, which
+/// [`AtomicWriter::commit`] syncs and renames over the original before syncing the directory and
+/// unlocking the file. Readers therefore see either the old contents or the new ones in full, and
+/// the ones still holding the old file open keep seeing the old contents. Dropping the writer
+/// without committing it, like when returning early on error or panicking, removes the temporary
+/// file and leaves the original untouched.
+///
+/// The lock is taken on the original file, created empty with [`LOCK_FILE_MODE`] if missing, and
+/// taken again if the file was replaced while waiting for it. Processes locking the file to read
+/// it should likewise check that the path still refers to the file they locked.
+#[derive(Debug)]
+pub struct AtomicWriter {
+    lock: OwnedFileLock<File, Exclusive>,
+    path: PathBuf,
+    temp: Temp,
+}
+
+/// Temporary file removed at [dropping][`Drop`] unless it was renamed.
+#[derive(Debug)]
+struct Temp {
+    file: File,
+    path: PathBuf,
+    renamed: bool,
+}
+
+impl Temp {
+    /// Renames the file to `to`, returning any error that could have caused.
+    fn rename(mut self, to: &Path) -> io::Result<()> {
+        fs::rename(&self.path, to)?;
+        self.renamed = true;
+        Ok(())
+    }
+
+    /// Removes the file, returning any error that could have caused.
+    fn remove(mut self) -> io::Result<()> {
+        self.renamed = true;
+        fs::remove_file(&self.path)
+    }
+}
+
+impl Drop for Temp {
+    fn drop(&mut self) {
+        if !self.renamed {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl AtomicWriter {
+    fn open(path: &Path, wait: bool) -> io::Result<Self> {
+        let lock = loop {
+            let file = open_lock_file(path, LOCK_FILE_MODE)?;
+            let lock = if wait {
+                OwnedFileLock::wrap_exclusive(file)?
+            } else {
+                OwnedFileLock::try_wrap_exclusive(file)?
+            };
+
+            match FileId::of_path(path) {
+                Ok(id) if id == FileId::of(&lock)? => break lock,
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                // Replaced or removed while waiting for the lock.
+                _ => (),
+            }
+        };
+        let temp = create_temp(path)?;
+        temp.file.set_permissions(lock.metadata()?.permissions())?;
+
+        Ok(Self {
+            lock,
+            path: path.to_owned(),
+            temp,
+        })
+    }
+
+    /// Creates a `Self` instance locking the file at `path` exclusive, waiting as long as needed,
+    /// and creating the temporary file next to it, returning any error that could have caused.
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open(path.as_ref(), true)
+    }
+
+    /// Creates a `Self` instance locking the file at `path` exclusive if it can be done without
+    /// waiting and creating the temporary file next to it, returning any error that could have
+    /// caused.
+    pub fn try_new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open(path.as_ref(), false)
+    }
+
+    /// Returns the original file, to read the contents being replaced.
+    #[inline(always)]
+    pub fn original(&self) -> &File {
+        &self.lock
+    }
+
+    /// Returns the path of the file being replaced.
+    #[inline(always)]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Syncs the new contents to disk, renames them over the original file, syncs its directory
+    /// and unlocks it, returning any error that could have caused.
+    ///
+    /// The original file is left untouched if anything before the rename fails.
+    pub fn commit(self) -> io::Result<()> {
+        let Self { lock, path, temp } = self;
+
+        temp.file.sync_all()?;
+        temp.rename(&path)?;
+        File::open(parent(&path))?.sync_all()?;
+        lock.unlock()
+    }
+
+    /// Removes the temporary file leaving the original untouched, returning any error that could
+    /// have caused instead of ignoring it like [dropping][`Drop`] does.
+    pub fn abort(self) -> io::Result<()> {
+        self.temp.remove()
+    }
+}
+
+impl Write for AtomicWriter {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.temp.file.write(buf)
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> io::Result<()> {
+        self.temp.file.flush()
+    }
+}
+
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Creates a new file next to `path` named after it, the process and a counter.
+fn create_temp(path: &Path) -> io::Result<Temp> {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path doesn't name a file"))?;
+
+    loop {
+        let mut temp_name = OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(
+            ".{}.{}.tmp",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let temp_path = parent(path).join(temp_name);
+
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+        {
+            Ok(file) => {
+                return Ok(Temp {
+                    file,
+                    path: temp_path,
+                    renamed: false,
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use ::std::{fmt::Debug, fs::File, io};
+
+use crate::LockMode;
+
+#[cfg(unix)]
+use crate::sys;
+
+/// Mechanism used to lock and unlock whole files.
+///
+/// Non-blocking attempts must fail with an error of kind [`io::ErrorKind::WouldBlock`] or with
+/// the platform's own contention error when the lock is held elsewhere, as that's what polling
+/// acquisitions keep retrying on.
+pub trait LockBackend: Debug + Send + Sync {
+    /// Locks `f` in `mode`, waiting as long as needed.
+    fn lock(&self, f: &File, mode: LockMode) -> io::Result<()>;
+
+    /// Locks `f` in `mode` if it can be done without waiting.
+    fn try_lock(&self, f: &File, mode: LockMode) -> io::Result<()>;
+
+    /// Unlocks `f`.
+    fn unlock(&self, f: &File) -> io::Result<()>;
+
+    /// Returns whether locking a file already locked through the same handle converts the lock
+    /// to the new mode without releasing it first, keeping it as it was if that fails.
+    ///
+    /// It's `false` by default.
+    #[inline(always)]
+    fn converts_atomically(&self) -> bool {
+        false
+    }
+
+    /// Returns whether locks belong to the process instead of the handle they were taken
+    /// through, so that the ones taken by the same process never conflict with each other.
+    ///
+    /// It's `false` by default.
+    #[inline(always)]
+    fn owned_by_process(&self) -> bool {
+        false
+    }
+}
+
+/// Backend over [`fs2::FileExt`], which takes `flock` locks on Unix and `LockFileEx` ones on
+/// Windows. This is the default.
+///
+/// `flock` locks belong to the open file description, so they're shared by duplicated
+/// descriptors and conflict with the ones taken through other descriptions of the same file even
+/// in the same process. They may be emulated with `fcntl` locks on NFS.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Flock;
+
+impl LockBackend for Flock {
+    fn lock(&self, f: &File, mode: LockMode) -> io::Result<()> {
+        match mode {
+            LockMode::Shared => fs2::FileExt::lock_shared(f),
+            LockMode::Exclusive => fs2::FileExt::lock_exclusive(f),
+        }
+    }
+
+    fn try_lock(&self, f: &File, mode: LockMode) -> io::Result<()> {
+        match mode {
+            LockMode::Shared => fs2::FileExt::try_lock_shared(f),
+            LockMode::Exclusive => fs2::FileExt::try_lock_exclusive(f),
+        }
+    }
+
+    #[inline(always)]
+    fn unlock(&self, f: &File) -> io::Result<()> {
+        fs2::FileExt::unlock(f)
+    }
+}
+
+/// Backend over the locking methods of the standard library like [`File::lock`], which take
+/// `flock` locks on most Unix platforms and `LockFileEx` ones on Windows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct StdLock;
+
+impl LockBackend for StdLock {
+    fn lock(&self, f: &File, mode: LockMode) -> io::Result<()> {
+        match mode {
+            LockMode::Shared => f.lock_shared(),
+            LockMode::Exclusive => f.lock(),
+        }
+    }
+
+    fn try_lock(&self, f: &File, mode: LockMode) -> io::Result<()> {
+        match mode {
+            LockMode::Shared => f.try_lock_shared(),
+            LockMode::Exclusive => f.try_lock(),
+        }
+        .map_err(io::Error::from)
+    }
+
+    #[inline(always)]
+    fn unlock(&self, f: &File) -> io::Result<()> {
+        f.unlock()
+    }
+}
+
+/// Backend over classic POSIX record locks taken with `fcntl` on the whole file.
+///
+/// They belong to the process instead of the open file description, so they never conflict with
+/// the ones taken by the same process and are all released as soon as it closes any descriptor
+/// of the file. Unlike `flock` locks they work over NFS and are converted atomically.
+///
+/// Exclusive locks require the file to be open for writing and shared ones for reading.
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PosixFcntl;
+
+#[cfg(unix)]
+impl LockBackend for PosixFcntl {
+    #[inline(always)]
+    fn lock(&self, f: &File, mode: LockMode) -> io::Result<()> {
+        sys::setlk(f, libc::F_SETLKW, Some(mode), 0, 0)
+    }
+
+    #[inline(always)]
+    fn try_lock(&self, f: &File, mode: LockMode) -> io::Result<()> {
+        sys::setlk(f, libc::F_SETLK, Some(mode), 0, 0)
+    }
+
+    #[inline(always)]
+    fn unlock(&self, f: &File) -> io::Result<()> {
+        sys::setlk(f, libc::F_SETLK, None, 0, 0)
+    }
+
+    #[inline(always)]
+    fn converts_atomically(&self) -> bool {
+        true
+    }
+
+    #[inline(always)]
+    fn owned_by_process(&self) -> bool {
+        true
+    }
+}
+
+/// Backend over Linux open file description locks taken with `fcntl` on the whole file, the same
+/// [`RangeLock`][`crate::RangeLock`] takes on part of it.
+///
+/// They belong to the open file description like `flock` locks but are record locks like
+/// [`PosixFcntl`] ones, so they work over NFS and are converted atomically.
+///
+/// Exclusive locks require the file to be open for writing and shared ones for reading.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct OfdFcntl;
+
+#[cfg(target_os = "linux")]
+impl LockBackend for OfdFcntl {
+    #[inline(always)]
+    fn lock(&self, f: &File, mode: LockMode) -> io::Result<()> {
+        sys::setlk(f, libc::F_OFD_SETLKW, Some(mode), 0, 0)
+    }
+
+    #[inline(always)]
+    fn try_lock(&self, f: &File, mode: LockMode) -> io::Result<()> {
+        sys::setlk(f, libc::F_OFD_SETLK, Some(mode), 0, 0)
+    }
+
+    #[inline(always)]
+    fn unlock(&self, f: &File) -> io::Result<()> {
+        sys::setlk(f, libc::F_OFD_SETLK, None, 0, 0)
+    }
+
+    #[inline(always)]
+    fn converts_atomically(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,140 @@
+use ::std::{
+    error::Error,
+    fmt, io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, TryRecvError},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Longest a cancellation that can only be polled goes unnoticed while waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Signal stopping cancellable acquisitions, which poll the lock and give up with a [`Cancelled`]
+/// error as soon as it's signalled instead of waiting for it.
+///
+/// It's implemented by [`CancelToken`], [`AtomicBool`], being signalled once set, and
+/// [`Receiver`], being signalled once a value is received or every sender is dropped.
+pub trait Cancellation {
+    /// Returns whether the acquisition has to give up.
+    fn is_cancelled(&self) -> bool;
+
+    /// Waits until the acquisition has to give up or `timeout` elapses, returning whether it
+    /// has to.
+    ///
+    /// The default implementation polls [`Cancellation::is_cancelled`] every few milliseconds.
+    fn wait_cancelled(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+
+        loop {
+            if self.is_cancelled() {
+                return true;
+            }
+
+            let left = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => POLL_INTERVAL,
+            };
+
+            if left == Duration::ZERO {
+                return false;
+            }
+
+            thread::sleep(left.min(POLL_INTERVAL));
+        }
+    }
+}
+
+/// Cloneable [`Cancellation`] waking the acquisitions waiting on any of its clones at once when
+/// [cancelled][`CancelToken::cancel`].
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancelToken {
+    /// Creates a token that isn't cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the acquisitions waiting on the token or any of its clones, and every one made
+    /// with them afterwards.
+    pub fn cancel(&self) {
+        let (cancelled, changed) = &*self.inner;
+        *cancelled.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        changed.notify_all();
+    }
+}
+
+impl Cancellation for CancelToken {
+    fn is_cancelled(&self) -> bool {
+        *self.inner.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wait_cancelled(&self, timeout: Duration) -> bool {
+        let (cancelled, changed) = &*self.inner;
+        let cancelled = cancelled.lock().unwrap_or_else(|e| e.into_inner());
+
+        match changed.wait_timeout_while(cancelled, timeout, |cancelled| !*cancelled) {
+            Ok((cancelled, _)) => *cancelled,
+            Err(e) => *e.into_inner().0,
+        }
+    }
+}
+
+impl Cancellation for AtomicBool {
+    #[inline(always)]
+    fn is_cancelled(&self) -> bool {
+        self.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Cancellation for Receiver<T> {
+    fn is_cancelled(&self) -> bool {
+        !matches!(self.try_recv(), Err(TryRecvError::Empty))
+    }
+
+    fn wait_cancelled(&self, timeout: Duration) -> bool {
+        !matches!(self.recv_timeout(timeout), Err(RecvTimeoutError::Timeout))
+    }
+}
+
+impl<T: Cancellation + ?Sized> Cancellation for Arc<T> {
+    #[inline(always)]
+    fn is_cancelled(&self) -> bool {
+        (**self).is_cancelled()
+    }
+
+    #[inline(always)]
+    fn wait_cancelled(&self, timeout: Duration) -> bool {
+        (**self).wait_cancelled(timeout)
+    }
+}
+
+/// Payload of the error of kind [`io::ErrorKind::Interrupted`] cancellable acquisitions give up
+/// with when their [`Cancellation`] is signalled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cancelled;
+
+impl Cancelled {
+    /// Returns the payload of `e` if it's the error a cancelled acquisition gave up with.
+    pub fn from_error(e: &io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("file lock acquisition cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+pub(crate) fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, Cancelled)
+}
@@ -0,0 +1,515 @@
+use ::std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt, io,
+    sync::{Mutex, MutexGuard, RwLock},
+    thread::{self, ThreadId},
+};
+
+use crate::{file_id::FileId, LockMode};
+
+/// What acquisitions closing a cycle of threads waiting for each other's file locks do, as
+/// detected when a [deadlock detection][`set_deadlock_detection`] is set.
+///
+/// While it's set every guard acquired through [`LockOptions`][`crate::LockOptions`], which all
+/// the constructors of the guards use, is recorded along with the thread that acquired it, and
+/// every acquisition that may wait along with the thread waiting. An acquisition that would wait
+/// for a file held in a conflicting mode by a thread that's itself waiting, directly or through
+/// others, for a file held by the acquiring thread is reported instead of waiting forever. This
+/// includes a thread locking a file it already holds through another handle, and blocking
+/// conversions of the guards between modes.
+///
+/// Locks taken with backends whose locks belong to the process, like
+/// [`PosixFcntl`][`crate::PosixFcntl`], aren't recorded as they never conflict within it.
+///
+/// Guards are recorded as held by the thread that acquired them even if moved to another one, and
+/// guards acquired before the detection was set aren't recorded at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeadlockDetection {
+    /// Fails with an error of kind [`io::ErrorKind::Deadlock`] with a [`DeadlockCycle`] payload.
+    Error,
+    /// Panics with the [`DeadlockCycle`].
+    Panic,
+}
+
+/// Thread of a [`DeadlockCycle`] waiting for a file lock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockedThread {
+    thread: ThreadId,
+    name: Option<String>,
+    file: FileId,
+    mode: LockMode,
+}
+
+impl BlockedThread {
+    /// Returns the identifier of the thread.
+    #[inline(always)]
+    pub fn thread(&self) -> ThreadId {
+        self.thread
+    }
+
+    /// Returns the name of the thread.
+    #[inline(always)]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the device number of the file the thread waits for.
+    #[inline(always)]
+    pub fn device(&self) -> u64 {
+        self.file.dev()
+    }
+
+    /// Returns the inode number of the file the thread waits for.
+    #[inline(always)]
+    pub fn inode(&self) -> u64 {
+        self.file.ino()
+    }
+
+    /// Returns the mode the thread waits to lock the file in.
+    #[inline(always)]
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+}
+
+impl fmt::Display for BlockedThread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "thread '{}'", name),
+            None => write!(f, "thread {:?}", self.thread),
+        }
+    }
+}
+
+/// Cycle of threads each waiting for a file lock held by the next one, the last one waiting for
+/// the first one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadlockCycle {
+    threads: Vec<BlockedThread>,
+}
+
+impl DeadlockCycle {
+    /// Returns the threads of the cycle, starting with the one whose acquisition closed it.
+    #[inline(always)]
+    pub fn threads(&self) -> &[BlockedThread] {
+        &self.threads
+    }
+
+    /// Returns the cycle `e` was reported with if any.
+    pub fn from_error(e: &io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for DeadlockCycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("file lock deadlock: ")?;
+
+        for (i, blocked) in self.threads.iter().enumerate() {
+            let holder = &self.threads[(i + 1) % self.threads.len()];
+            let mode = match blocked.mode {
+                LockMode::Shared => "shared",
+                LockMode::Exclusive => "exclusive",
+            };
+
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+
+            write!(
+                f,
+                "{} waits to lock file {}:{} {}, held by {}",
+                blocked,
+                blocked.device(),
+                blocked.inode(),
+                mode,
+                holder
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for DeadlockCycle {}
+
+/// Locks held and waited for by the threads of the process.
+struct Graph {
+    held: BTreeMap<FileId, Vec<(ThreadId, LockMode)>>,
+    waiting: Vec<BlockedThread>,
+}
+
+static GRAPH: Mutex<Graph> = Mutex::new(Graph {
+    held: BTreeMap::new(),
+    waiting: Vec::new(),
+});
+static DETECTION: RwLock<Option<DeadlockDetection>> = RwLock::new(None);
+
+/// Sets what acquisitions closing a cycle do, `None` meaning guards aren't recorded. This is the
+/// default.
+pub fn set_deadlock_detection(detection: Option<DeadlockDetection>) {
+    *DETECTION.write().unwrap_or_else(|e| e.into_inner()) = detection;
+}
+
+/// Returns what acquisitions closing a cycle do.
+pub fn deadlock_detection() -> Option<DeadlockDetection> {
+    *DETECTION.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn graph() -> MutexGuard<'static, Graph> {
+    GRAPH.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn conflicts(a: LockMode, b: LockMode) -> bool {
+    a == LockMode::Exclusive || b == LockMode::Exclusive
+}
+
+impl Graph {
+    /// Looks for a path from the last thread of `path` back to `start`, each thread waiting for a
+    /// file held by the next one, ignoring a lock `start` holds in the `skip` mode on the first
+    /// file.
+    fn find_cycle(
+        &self,
+        start: ThreadId,
+        path: &mut Vec<BlockedThread>,
+        visited: &mut Vec<ThreadId>,
+        mut skip: Option<LockMode>,
+    ) -> bool {
+        let (file, mode) = match path.last() {
+            Some(blocked) => (blocked.file, blocked.mode),
+            None => return false,
+        };
+        let holders = self.held.get(&file).map_or(&[][..], |holders| holders);
+
+        for &(holder, held) in holders {
+            if holder == start && skip == Some(held) {
+                skip = None;
+                continue;
+            }
+
+            if !conflicts(mode, held) {
+                continue;
+            }
+
+            if holder == start {
+                return true;
+            }
+
+            if visited.contains(&holder) {
+                continue;
+            }
+
+            visited.push(holder);
+
+            if let Some(blocked) = self.waiting.iter().find(|w| w.thread == holder) {
+                path.push(blocked.clone());
+
+                if self.find_cycle(start, path, visited, None) {
+                    return true;
+                }
+
+                path.pop();
+            }
+        }
+
+        false
+    }
+
+    fn hold(&mut self, file: FileId, thread: ThreadId, mode: LockMode) {
+        self.held.entry(file).or_default().push((thread, mode));
+    }
+
+    fn release(&mut self, file: FileId, thread: ThreadId, mode: LockMode) {
+        if let Some(holders) = self.held.get_mut(&file) {
+            if let Some(i) = holders.iter().position(|held| *held == (thread, mode)) {
+                holders.swap_remove(i);
+            }
+
+            if holders.is_empty() {
+                self.held.remove(&file);
+            }
+        }
+    }
+}
+
+/// Returns the current thread as waiting for `file` in `mode`.
+fn blocked(file: FileId, mode: LockMode) -> BlockedThread {
+    let current = thread::current();
+
+    BlockedThread {
+        thread: current.id(),
+        name: current.name().map(str::to_owned),
+        file,
+        mode,
+    }
+}
+
+/// Reports `cycle` following `detection`.
+fn report(detection: DeadlockDetection, cycle: DeadlockCycle) -> io::Error {
+    match detection {
+        DeadlockDetection::Error => io::Error::new(io::ErrorKind::Deadlock, cycle),
+        DeadlockDetection::Panic => panic!("{}", cycle),
+    }
+}
+
+/// Records the current thread as waiting for `file` in `mode` until the returned value is
+/// dropped, reporting the cycle following `detection` instead if that closes one.
+pub(crate) fn wait(
+    detection: DeadlockDetection,
+    file: FileId,
+    mode: LockMode,
+) -> io::Result<Waiting> {
+    let blocked = blocked(file, mode);
+    let thread = blocked.thread;
+    let mut graph = graph();
+    let mut path = vec![blocked.clone()];
+
+    if graph.find_cycle(thread, &mut path, &mut Vec::new(), None) {
+        drop(graph);
+        return Err(report(detection, DeadlockCycle { threads: path }));
+    }
+
+    graph.waiting.push(blocked);
+    Ok(Waiting {
+        thread,
+        released: None,
+    })
+}
+
+/// Record of a thread waiting for a file lock.
+pub(crate) struct Waiting {
+    thread: ThreadId,
+    /// Lock released while waiting, recorded again once done.
+    released: Option<(FileId, LockMode)>,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        let mut graph = graph();
+        graph.waiting.retain(|w| w.thread != self.thread);
+
+        if let Some((file, mode)) = self.released {
+            graph.hold(file, self.thread, mode);
+        }
+    }
+}
+
+/// Record of a guard holding a file lock.
+#[derive(Debug)]
+pub(crate) struct Held {
+    thread: ThreadId,
+    file: FileId,
+    mode: LockMode,
+}
+
+impl Held {
+    /// Records the current thread as holding `file` in `mode` until [`Held::release`] is called.
+    pub(crate) fn new(file: FileId, mode: LockMode) -> Self {
+        let thread = thread::current().id();
+        graph().hold(file, thread, mode);
+
+        Self { thread, file, mode }
+    }
+
+    /// Records the current thread as waiting to convert the lock to `mode` until the returned
+    /// value is dropped like [`wait`] does, without it waiting for the lock itself.
+    ///
+    /// If the lock isn't `kept` while waiting, as the conversion releases it first, it isn't
+    /// recorded as held until then either.
+    pub(crate) fn wait_converted(
+        &self,
+        detection: DeadlockDetection,
+        mode: LockMode,
+        kept: bool,
+    ) -> io::Result<Waiting> {
+        let blocked = blocked(self.file, mode);
+        let mut graph = graph();
+        let mut path = vec![blocked.clone()];
+
+        if !kept {
+            graph.release(self.file, self.thread, self.mode);
+        }
+
+        let skip = Some(self.mode).filter(|_| kept);
+
+        if graph.find_cycle(self.thread, &mut path, &mut Vec::new(), skip) {
+            if !kept {
+                graph.hold(self.file, self.thread, self.mode);
+            }
+
+            drop(graph);
+            return Err(report(detection, DeadlockCycle { threads: path }));
+        }
+
+        graph.waiting.push(blocked);
+        Ok(Waiting {
+            thread: self.thread,
+            released: Some((self.file, self.mode)).filter(|_| !kept),
+        })
+    }
+
+    /// Records the lock as converted to `mode`.
+    pub(crate) fn converted(&mut self, mode: LockMode) {
+        let mut graph = graph();
+
+        if let Some(held) = graph.held.get_mut(&self.file).and_then(|holders| {
+            holders
+                .iter_mut()
+                .find(|held| **held == (self.thread, self.mode))
+        }) {
+            held.1 = mode;
+        }
+
+        self.mode = mode;
+    }
+
+    /// Removes the record.
+    pub(crate) fn release(&self) {
+        graph().release(self.file, self.thread, self.mode);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use LockMode::{Exclusive, Shared};
+
+    fn thread_id() -> ThreadId {
+        thread::spawn(|| thread::current().id()).join().unwrap()
+    }
+
+    fn file(ino: u64) -> FileId {
+        FileId::new(1, ino)
+    }
+
+    fn waits(thread: ThreadId, file: FileId, mode: LockMode) -> BlockedThread {
+        BlockedThread {
+            thread,
+            name: None,
+            file,
+            mode,
+        }
+    }
+
+    fn new_graph(held: &[(FileId, ThreadId, LockMode)], waiting: &[BlockedThread]) -> Graph {
+        let mut graph = Graph {
+            held: BTreeMap::new(),
+            waiting: waiting.to_vec(),
+        };
+
+        for &(file, thread, mode) in held {
+            graph.hold(file, thread, mode);
+        }
+
+        graph
+    }
+
+    /// Returns the threads of the cycle closed by `waiting`, if any.
+    fn cycle(
+        graph: &Graph,
+        waiting: BlockedThread,
+        skip: Option<LockMode>,
+    ) -> Option<Vec<ThreadId>> {
+        let start = waiting.thread;
+        let mut path = vec![waiting];
+
+        if graph.find_cycle(start, &mut path, &mut Vec::new(), skip) {
+            Some(path.iter().map(BlockedThread::thread).collect())
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn waiting_for_idle_holder_is_no_cycle() {
+        let (a, b) = (thread_id(), thread_id());
+        let graph = new_graph(&[(file(1), b, Exclusive), (file(2), a, Exclusive)], &[]);
+
+        assert_eq!(cycle(&graph, waits(a, file(1), Shared), None), None);
+    }
+
+    #[test]
+    fn finds_cycle_of_two_threads() {
+        let (a, b) = (thread_id(), thread_id());
+        let graph = new_graph(
+            &[(file(1), b, Exclusive), (file(2), a, Shared)],
+            &[waits(b, file(2), Exclusive)],
+        );
+
+        assert_eq!(
+            cycle(&graph, waits(a, file(1), Shared), None),
+            Some(vec![a, b])
+        );
+    }
+
+    #[test]
+    fn finds_cycle_of_three_threads() {
+        let (a, b, c) = (thread_id(), thread_id(), thread_id());
+        let graph = new_graph(
+            &[
+                (file(1), b, Exclusive),
+                (file(2), c, Exclusive),
+                (file(3), a, Exclusive),
+            ],
+            &[waits(b, file(2), Shared), waits(c, file(3), Shared)],
+        );
+
+        assert_eq!(
+            cycle(&graph, waits(a, file(1), Exclusive), None),
+            Some(vec![a, b, c])
+        );
+    }
+
+    #[test]
+    fn shared_locks_never_conflict() {
+        let (a, b) = (thread_id(), thread_id());
+        let graph = new_graph(
+            &[(file(1), b, Shared), (file(2), a, Shared)],
+            &[waits(b, file(2), Shared)],
+        );
+
+        assert_eq!(cycle(&graph, waits(a, file(1), Shared), None), None);
+    }
+
+    #[test]
+    fn finds_thread_waiting_for_itself() {
+        let a = thread_id();
+        let graph = new_graph(&[(file(1), a, Shared)], &[]);
+
+        assert_eq!(
+            cycle(&graph, waits(a, file(1), Exclusive), None),
+            Some(vec![a])
+        );
+    }
+
+    #[test]
+    fn conversion_skips_its_own_lock() {
+        let a = thread_id();
+        let graph = new_graph(&[(file(1), a, Shared)], &[]);
+        assert_eq!(
+            cycle(&graph, waits(a, file(1), Exclusive), Some(Shared)),
+            None
+        );
+
+        let graph = new_graph(&[(file(1), a, Shared), (file(1), a, Shared)], &[]);
+        assert_eq!(
+            cycle(&graph, waits(a, file(1), Exclusive), Some(Shared)),
+            Some(vec![a])
+        );
+    }
+
+    #[test]
+    fn finds_cycle_of_two_conversions() {
+        let (a, b) = (thread_id(), thread_id());
+        let graph = new_graph(
+            &[(file(1), a, Shared), (file(1), b, Shared)],
+            &[waits(b, file(1), Exclusive)],
+        );
+
+        assert_eq!(
+            cycle(&graph, waits(a, file(1), Exclusive), Some(Shared)),
+            Some(vec![a, b])
+        );
+    }
+}
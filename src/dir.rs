@@ -0,0 +1,441 @@
+use ::std::{
+    ffi::{CStr, CString, OsStr},
+    fs::{File, OpenOptions},
+    io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::OpenOptionsExt,
+        io::{AsRawFd, FromRawFd},
+    },
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    Acquisition, DropPolicy, Exclusive, LockMode, LockOptions, Mode, OwnedFileLock, RetryPolicy,
+    Shared,
+};
+
+/// Guard over a directory, locking either a handle to the directory itself or a well-known lock
+/// file inside it, see [`DirLockOptions`].
+///
+/// It also opens files relative to the directory through a handle to it, refusing paths that
+/// would lead out of it with `..` components or symbolic links. `M` is either [`Shared`] or
+/// [`Exclusive`], only the latter creating or writing files.
+///
+/// Directory handles are open read-only, so exclusive locks on them can only be taken with
+/// backends that don't require the file to be open for writing like [`Flock`][`crate::Flock`].
+#[derive(Debug)]
+pub struct DirLock<M: Mode> {
+    lock: OwnedFileLock<File, M>,
+    /// Handle to the directory files are opened relative to.
+    dir: File,
+    path: PathBuf,
+}
+
+/// Settings to take [`DirLock`]s with.
+///
+/// By default the directory handle itself is locked with [`LockOptions::new`].
+#[derive(Clone, Debug)]
+pub struct DirLockOptions {
+    options: LockOptions,
+    lock_file: Option<PathBuf>,
+}
+
+impl DirLockOptions {
+    /// Creates options with the default settings.
+    pub fn new() -> Self {
+        Self {
+            options: LockOptions::new(),
+            lock_file: None,
+        }
+    }
+
+    /// Sets the options the lock is taken with.
+    pub fn options(mut self, options: LockOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets the name of a lock file inside the directory to lock in place of its handle, created
+    /// with the [file mode][`LockOptions::file_mode`] of the options if missing.
+    ///
+    /// It's opened relative to the directory handle like [`DirLock::open`] does, so it can't be a
+    /// symbolic link nor lead out of the directory.
+    pub fn lock_file<P: Into<PathBuf>>(mut self, name: P) -> Self {
+        self.lock_file = Some(name.into());
+        self
+    }
+
+    /// Opens the file to lock and a handle to the directory at `path`.
+    fn open<M: Mode>(&self, path: &Path) -> io::Result<(File, File, PathBuf)> {
+        let dir = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY)
+            .open(path)?;
+        let file = match &self.lock_file {
+            Some(name) => self.open_lock_file(&dir, name, M::MODE)?,
+            None => dir.try_clone()?,
+        };
+
+        Ok((file, dir, path.to_owned()))
+    }
+
+    /// Opens the lock file `name` inside `dir` for a guard locking it in `mode`, read-only if it's
+    /// shared and the file exists but can't be opened for writing.
+    fn open_lock_file(&self, dir: &File, name: &Path, mode: LockMode) -> io::Result<File> {
+        let (parent, name) = walk(dir, name)?;
+        let parent = parent.as_ref().unwrap_or(dir);
+        let flags = libc::O_RDWR | libc::O_CREAT;
+
+        match openat(parent, &name, flags, self.options.lock_file_mode()) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied && mode == LockMode::Shared => {
+                openat(parent, &name, libc::O_RDONLY, 0).map_err(|_| e)
+            }
+            r => r,
+        }
+    }
+
+    /// Creates a [`DirLock`] locking the directory at `path` if it can be done without waiting and
+    /// returning any error that could have caused.
+    pub fn try_lock<P: AsRef<Path>, M: Mode>(&self, path: P) -> io::Result<DirLock<M>> {
+        let (file, dir, path) = self.open::<M>(path.as_ref())?;
+        let lock = self.options.try_wrap(file)?;
+        Ok(DirLock { lock, dir, path })
+    }
+
+    /// Creates a [`DirLock`] locking the directory at `path`, waiting as long as needed, and
+    /// returning any error that could have caused.
+    pub fn lock<P: AsRef<Path>, M: Mode>(&self, path: P) -> io::Result<DirLock<M>> {
+        let (file, dir, path) = self.open::<M>(path.as_ref())?;
+        let lock = self.options.wrap(file)?;
+        Ok(DirLock { lock, dir, path })
+    }
+
+    /// Creates a [`DirLock`] polling until the directory at `path` is locked or `timeout`
+    /// elapses, returning an error of kind [`io::ErrorKind::TimedOut`] in the latter case or any
+    /// other error that could have caused.
+    pub fn lock_timeout<P: AsRef<Path>, M: Mode>(
+        &self,
+        path: P,
+        timeout: Duration,
+    ) -> io::Result<DirLock<M>> {
+        let (file, dir, path) = self.open::<M>(path.as_ref())?;
+        let lock = self.options.wrap_timeout(file, timeout)?;
+        Ok(DirLock { lock, dir, path })
+    }
+
+    /// Creates a [`DirLock`] polling until the directory at `path` is locked following `policy`
+    /// and returning any error that could have caused, see [`RetryPolicy`] for the ones it gives
+    /// up with.
+    pub fn lock_with_retry<P: AsRef<Path>, M: Mode>(
+        &self,
+        path: P,
+        policy: &RetryPolicy,
+    ) -> io::Result<DirLock<M>> {
+        let (file, dir, path) = self.open::<M>(path.as_ref())?;
+        let lock = self.options.wrap_with_retry(file, policy)?;
+        Ok(DirLock { lock, dir, path })
+    }
+}
+
+impl Default for DirLockOptions {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Mode> DirLock<M> {
+    /// Returns the path of the locked directory.
+    #[inline(always)]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the mode the lock is held in, which is always `M::MODE`.
+    #[inline(always)]
+    pub fn mode(&self) -> LockMode {
+        self.lock.mode()
+    }
+
+    /// Returns how the lock was acquired.
+    #[inline(always)]
+    pub fn acquisition(&self) -> Acquisition {
+        self.lock.acquisition()
+    }
+
+    /// Returns when the lock was acquired.
+    #[inline(always)]
+    pub fn acquired_at(&self) -> Instant {
+        self.lock.acquired_at()
+    }
+
+    /// Sets the policy followed if unlocking fails at [dropping][`Drop`], overriding the global
+    /// one.
+    #[inline(always)]
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.lock.set_drop_policy(policy)
+    }
+
+    /// Returns the path of `name` inside the directory, failing with an error of kind
+    /// [`io::ErrorKind::InvalidInput`] if it's absolute or has `..` components.
+    ///
+    /// Unlike the methods opening files it doesn't check for symbolic links, which may still lead
+    /// out of the directory.
+    pub fn join<P: AsRef<Path>>(&self, name: P) -> io::Result<PathBuf> {
+        join(&self.path, name.as_ref())
+    }
+
+    /// Opens the file `name` inside the directory read-only, failing with an error of kind
+    /// [`io::ErrorKind::InvalidInput`] if it's absolute or has `..` components and with the one
+    /// `openat` fails with if any of them is a symbolic link.
+    pub fn open<P: AsRef<Path>>(&self, name: P) -> io::Result<File> {
+        let (parent, name) = walk(&self.dir, name.as_ref())?;
+        openat(
+            parent.as_ref().unwrap_or(&self.dir),
+            &name,
+            libc::O_RDONLY,
+            0,
+        )
+    }
+
+    /// Unlocks the directory returning any error that could have caused instead of reporting it
+    /// like [dropping][`Drop`] does.
+    #[inline(always)]
+    pub fn unlock(self) -> io::Result<()> {
+        self.lock.unlock()
+    }
+}
+
+impl DirLock<Shared> {
+    /// Creates a `Self` instance locking the directory at `path` shared with the default
+    /// [`DirLockOptions`], see [`DirLockOptions::try_lock`].
+    pub fn try_open_shared<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        DirLockOptions::new().try_lock(path)
+    }
+
+    /// Creates a `Self` instance locking the directory at `path` shared with the default
+    /// [`DirLockOptions`], see [`DirLockOptions::lock`].
+    pub fn open_shared<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        DirLockOptions::new().lock(path)
+    }
+
+    /// Creates a `Self` instance locking the directory at `path` shared with the default
+    /// [`DirLockOptions`], see [`DirLockOptions::lock_timeout`].
+    pub fn open_shared_timeout<P: AsRef<Path>>(path: P, timeout: Duration) -> io::Result<Self> {
+        DirLockOptions::new().lock_timeout(path, timeout)
+    }
+}
+
+impl DirLock<Exclusive> {
+    /// Creates a `Self` instance locking the directory at `path` exclusive with the default
+    /// [`DirLockOptions`], see [`DirLockOptions::try_lock`].
+    pub fn try_open_exclusive<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        DirLockOptions::new().try_lock(path)
+    }
+
+    /// Creates a `Self` instance locking the directory at `path` exclusive with the default
+    /// [`DirLockOptions`], see [`DirLockOptions::lock`].
+    pub fn open_exclusive<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        DirLockOptions::new().lock(path)
+    }
+
+    /// Creates a `Self` instance locking the directory at `path` exclusive with the default
+    /// [`DirLockOptions`], see [`DirLockOptions::lock_timeout`].
+    pub fn open_exclusive_timeout<P: AsRef<Path>>(path: P, timeout: Duration) -> io::Result<Self> {
+        DirLockOptions::new().lock_timeout(path, timeout)
+    }
+
+    /// Creates the file `name` inside the directory, truncating it if it exists, and opens it for
+    /// writing, see [`DirLock::open`].
+    pub fn create<P: AsRef<Path>>(&self, name: P) -> io::Result<File> {
+        let (parent, name) = walk(&self.dir, name.as_ref())?;
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC;
+        openat(parent.as_ref().unwrap_or(&self.dir), &name, flags, 0o666)
+    }
+
+    /// Opens the file `name` inside the directory with `options`, see [`DirLock::open`].
+    ///
+    /// It's opened through `/proc/self/fd` with the custom flags of `options` replaced by
+    /// `O_NOFOLLOW`.
+    #[cfg(target_os = "linux")]
+    pub fn open_with<P: AsRef<Path>>(&self, name: P, options: &OpenOptions) -> io::Result<File> {
+        let (parent, name) = walk(&self.dir, name.as_ref())?;
+        let parent = parent.as_ref().unwrap_or(&self.dir);
+        let path = Path::new("/proc/self/fd")
+            .join(parent.as_raw_fd().to_string())
+            .join(OsStr::from_bytes(name.as_bytes()));
+
+        options.clone().custom_flags(libc::O_NOFOLLOW).open(path)
+    }
+
+    /// Creates the directory `name` inside the directory, see [`DirLock::open`].
+    pub fn create_dir<P: AsRef<Path>>(&self, name: P) -> io::Result<()> {
+        let (parent, name) = walk(&self.dir, name.as_ref())?;
+        let parent = parent.as_ref().unwrap_or(&self.dir);
+
+        // SAFETY: `name` is a valid C string and the descriptor is kept open by `parent`.
+        if unsafe { libc::mkdirat(parent.as_raw_fd(), name.as_ptr(), 0o777) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+fn leads_out() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "path leads out of the locked directory",
+    )
+}
+
+/// Joins `name` to `dir` as long as it stays inside it.
+fn join(dir: &Path, name: &Path) -> io::Result<PathBuf> {
+    let inside = name.components().next().is_some()
+        && name
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+
+    if !inside {
+        return Err(leads_out());
+    }
+
+    Ok(dir.join(name))
+}
+
+/// Opens the directories leading to `name` inside `dir` without following symbolic links,
+/// returning the innermost one, `None` standing for `dir` itself, and the last component of
+/// `name`.
+fn walk(dir: &File, name: &Path) -> io::Result<(Option<File>, CString)> {
+    let mut names = Vec::new();
+
+    for component in name.components() {
+        match component {
+            Component::Normal(name) => names.push(name),
+            Component::CurDir => (),
+            _ => return Err(leads_out()),
+        }
+    }
+
+    let last = names.pop().ok_or_else(leads_out)?;
+    let mut parent: Option<File> = None;
+
+    for name in names {
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY;
+        parent = Some(openat(
+            parent.as_ref().unwrap_or(dir),
+            &c_name(name)?,
+            flags,
+            0,
+        )?);
+    }
+
+    Ok((parent, c_name(last)?))
+}
+
+fn c_name(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Opens `name` inside `dir` with `flags`, creating it with `mode` if asked to, without following
+/// it if it's a symbolic link.
+fn openat(dir: &File, name: &CStr, flags: libc::c_int, mode: libc::c_uint) -> io::Result<File> {
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    // SAFETY: `name` is a valid C string and the descriptor is kept open by `dir`.
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, mode) };
+
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: the descriptor was just returned by `openat` and is owned by nothing else.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, os::unix::fs::symlink, process};
+
+    /// Directory holding a locked `dir` and a file `outside` it, removed at dropping.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("raii_flock-dir-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("dir/sub")).unwrap();
+            fs::write(path.join("outside"), "").unwrap();
+            Self(path)
+        }
+
+        fn dir(&self) -> PathBuf {
+            self.0.join("dir")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn join_keeps_paths_inside() {
+        let dir = Path::new("/locked");
+
+        assert_eq!(join(dir, Path::new("a")).unwrap(), dir.join("a"));
+        assert_eq!(join(dir, Path::new("./a/b")).unwrap(), dir.join("./a/b"));
+        assert_eq!(join(dir, Path::new(".")).unwrap(), dir.join("."));
+
+        for name in ["", "/etc/passwd", "..", "../a", "a/../../b", "a/.."] {
+            let e = join(dir, Path::new(name)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn opens_files_inside() {
+        let temp = TempDir::new("inside");
+        let lock = DirLock::try_open_exclusive(temp.dir()).unwrap();
+
+        lock.create_dir("sub/new").unwrap();
+        lock.create("sub/new/file").unwrap();
+        assert!(lock.open("./sub/new/file").is_ok());
+        assert!(temp.dir().join("sub/new/file").exists());
+    }
+
+    #[test]
+    fn refuses_paths_leading_out() {
+        let temp = TempDir::new("out");
+        symlink(&temp.0, temp.dir().join("link")).unwrap();
+        symlink(temp.0.join("outside"), temp.dir().join("file-link")).unwrap();
+        let lock = DirLock::try_open_exclusive(temp.dir()).unwrap();
+
+        for name in ["../outside", "/etc/passwd", "", ".", "sub/.."] {
+            let e = lock.open(name).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+
+        assert!(lock.open("link/outside").is_err());
+        assert!(lock.open("file-link").is_err());
+        assert!(lock.create("link/created").is_err());
+        assert!(lock.create_dir("link/created").is_err());
+        assert!(!temp.0.join("created").exists());
+    }
+
+    #[test]
+    fn refuses_lock_file_leading_out() {
+        let temp = TempDir::new("lock-file");
+        symlink(temp.0.join("outside"), temp.dir().join(".lock")).unwrap();
+        let options = DirLockOptions::new().lock_file(".lock");
+
+        assert!(options.try_lock::<_, Exclusive>(temp.dir()).is_err());
+        assert!(options
+            .lock_file("../outside")
+            .try_lock::<_, Shared>(temp.dir())
+            .is_err());
+    }
+}
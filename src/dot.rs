@@ -0,0 +1,573 @@
+use ::std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io,
+    mem::ManuallyDrop,
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    acquire::{self, Retry, Wait},
+    file_id::FileId,
+    info, policy, DropPolicy, LockInfo, RetryPolicy, LOCK_FILE_MODE,
+};
+
+/// How long a `.break` file left behind by a process that crashed while breaking a lock is kept
+/// before being removed.
+const BREAK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Lock held by the presence of a file, `name.lock` for a file `name`, for filesystems where
+/// `flock` can't be trusted.
+///
+/// The lock file is created atomically, either with `O_EXCL` or by hard-linking a uniquely named
+/// file to it as needed on old NFS versions, and holds the [`LockInfo`] of its owner. It's removed
+/// at [dropping][`Drop`] as long as it's still the one created by the guard, having been broken
+/// being reported as an error otherwise.
+///
+/// Lock files left behind by processes that died can be broken, see [`DotLockOptions`].
+#[derive(Debug)]
+pub struct DotLock {
+    file: File,
+    path: PathBuf,
+    info: LockInfo,
+    policy: Option<DropPolicy>,
+}
+
+/// Settings to take [`DotLock`]s with.
+///
+/// By default lock files are created with `O_EXCL` and only broken if their owner ran on the
+/// same host and is dead.
+#[derive(Clone, Debug)]
+pub struct DotLockOptions {
+    link: bool,
+    stale_after: Option<Duration>,
+    break_dead: bool,
+    info: Option<LockInfo>,
+}
+
+impl DotLockOptions {
+    /// Creates options with the default settings.
+    pub fn new() -> Self {
+        Self {
+            link: false,
+            stale_after: None,
+            break_dead: true,
+            info: None,
+        }
+    }
+
+    /// Sets whether lock files are created by hard-linking a uniquely named file to them instead
+    /// of with `O_EXCL`, which isn't atomic on NFS before version 3.
+    pub fn link(mut self, link: bool) -> Self {
+        self.link = link;
+        self
+    }
+
+    /// Breaks lock files that weren't modified for `age`, whoever their owner is.
+    pub fn stale_after(mut self, age: Duration) -> Self {
+        self.stale_after = Some(age);
+        self
+    }
+
+    /// Sets whether lock files whose owner ran on the same host and is no longer alive are
+    /// broken.
+    pub fn break_dead(mut self, break_dead: bool) -> Self {
+        self.break_dead = break_dead;
+        self
+    }
+
+    /// Sets the record written into lock files in place of [`LockInfo::current`], the time
+    /// being updated on locking.
+    pub fn info(mut self, info: LockInfo) -> Self {
+        self.info = Some(info);
+        self
+    }
+
+    /// Creates a [`DotLock`] for the file at `path` if it can be done without waiting, returning
+    /// an error of kind [`io::ErrorKind::WouldBlock`] if its lock file is held or any other error
+    /// that could have caused.
+    pub fn try_lock<P: AsRef<Path>>(&self, path: P) -> io::Result<DotLock> {
+        self.lock_with(path.as_ref(), Wait::No)
+    }
+
+    /// Creates a [`DotLock`] for the file at `path`, polling with the default [`RetryPolicy`] as
+    /// long as needed and returning any error that could have caused.
+    pub fn lock<P: AsRef<Path>>(&self, path: P) -> io::Result<DotLock> {
+        self.lock_with(path.as_ref(), Wait::Blocking)
+    }
+
+    /// Creates a [`DotLock`] for the file at `path`, polling with the default [`RetryPolicy`]
+    /// until it succeeds or `timeout` elapses, returning an error of kind
+    /// [`io::ErrorKind::TimedOut`] in the latter case or any other error that could have caused.
+    pub fn lock_timeout<P: AsRef<Path>>(&self, path: P, timeout: Duration) -> io::Result<DotLock> {
+        self.lock_with(path.as_ref(), Wait::timeout(timeout))
+    }
+
+    /// Creates a [`DotLock`] for the file at `path`, polling following `policy` and returning any
+    /// error that could have caused, see [`RetryPolicy`] for the ones it gives up with.
+    pub fn lock_with_retry<P: AsRef<Path>>(
+        &self,
+        path: P,
+        policy: &RetryPolicy,
+    ) -> io::Result<DotLock> {
+        self.lock_with(path.as_ref(), Wait::Retry(policy.clone(), None))
+    }
+
+    fn lock_with(&self, path: &Path, wait: Wait) -> io::Result<DotLock> {
+        let path = DotLock::lock_path(path);
+        let (policy, deadline) = match wait {
+            Wait::No => return self.try_lock_path(&path),
+            Wait::Retry(policy, deadline) => (policy, deadline),
+            _ => (RetryPolicy::default(), None),
+        };
+        let mut retry = Retry::new(&policy, deadline);
+
+        loop {
+            match self.try_lock_path(&path) {
+                Err(e) if acquire::is_contended(&e) => thread::sleep(retry.failed(e)?),
+                r => return r,
+            }
+        }
+    }
+
+    fn try_lock_path(&self, path: &Path) -> io::Result<DotLock> {
+        match self.create(path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if self.break_stale(path)? {
+                    return self.create(path).map_err(contended);
+                }
+
+                Err(contended(e))
+            }
+            r => r,
+        }
+    }
+
+    /// Creates the lock file at `path`, failing with an error of kind
+    /// [`io::ErrorKind::AlreadyExists`] if it's already there.
+    fn create(&self, path: &Path) -> io::Result<DotLock> {
+        let info = match &self.info {
+            Some(info) => info.now(),
+            None => LockInfo::current(),
+        };
+
+        let file = if self.link {
+            let (file, unique) = create_unique(path)?;
+            let r = info.write_to(&file).and_then(|()| {
+                // The link may have been created even if an error was reported over NFS, so the
+                // link count is what tells whether it was.
+                let _ = fs::hard_link(&unique, path);
+
+                if file.metadata()?.nlink() == 2 {
+                    Ok(())
+                } else {
+                    Err(io::ErrorKind::AlreadyExists.into())
+                }
+            });
+            let _ = fs::remove_file(&unique);
+            r.map(|()| file)?
+        } else {
+            let file = create_new(path)?;
+
+            if let Err(e) = info.write_to(&file) {
+                let _ = fs::remove_file(path);
+                return Err(e);
+            }
+
+            file
+        };
+
+        Ok(DotLock {
+            file,
+            path: path.to_owned(),
+            info,
+            policy: None,
+        })
+    }
+
+    /// Returns whether the lock file at `path` is stale according to its record `info`.
+    fn is_stale(&self, path: &Path, info: Option<&LockInfo>) -> io::Result<bool> {
+        if let Some(age) = self.stale_after {
+            let modified = fs::metadata(path)?.modified()?;
+
+            if SystemTime::now()
+                .duration_since(modified)
+                .is_ok_and(|elapsed| elapsed > age)
+            {
+                return Ok(true);
+            }
+        }
+
+        Ok(match info {
+            Some(info) if self.break_dead && info.hostname() == info::hostname().as_deref() => {
+                is_dead(info.pid())
+            }
+            _ => false,
+        })
+    }
+
+    /// Removes the lock file at `path` if it's stale, returning whether it was.
+    ///
+    /// Breakers take turns by creating a `.break` file next to it with `O_EXCL` and check that the
+    /// lock file is still the one found stale before removing it, so a lock file taken again
+    /// after being broken isn't broken too by a process that found the old one stale.
+    fn break_stale(&self, path: &Path) -> io::Result<bool> {
+        let found = match read(path)? {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+
+        if !self.is_stale(path, found.1.as_ref())? {
+            return Ok(false);
+        }
+
+        let break_path = suffixed(path, ".break");
+        let _break = match create_new(&break_path) {
+            Ok(_) => BreakFile(&break_path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let modified = fs::metadata(&break_path).and_then(|m| m.modified());
+
+                if modified.is_ok_and(|modified| {
+                    SystemTime::now()
+                        .duration_since(modified)
+                        .is_ok_and(|elapsed| elapsed > BREAK_TIMEOUT)
+                }) {
+                    let _ = fs::remove_file(&break_path);
+                }
+
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+
+        remove_unchanged(path, &found)
+    }
+}
+
+impl Default for DotLockOptions {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DotLock {
+    /// Returns the path of the lock file for the file at `path`, `path` with `.lock` appended.
+    pub fn lock_path<P: AsRef<Path>>(path: P) -> PathBuf {
+        suffixed(path.as_ref(), ".lock")
+    }
+
+    /// Returns the record of the owner of the lock for the file at `path` if it's held.
+    pub fn holder<P: AsRef<Path>>(path: P) -> io::Result<Option<LockInfo>> {
+        match LockInfo::read(Self::lock_path(path)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            r => r,
+        }
+    }
+
+    /// Creates a `Self` instance with the default [`DotLockOptions`] if it can be done without
+    /// waiting, see [`DotLockOptions::try_lock`].
+    pub fn try_new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        DotLockOptions::new().try_lock(path)
+    }
+
+    /// Creates a `Self` instance with the default [`DotLockOptions`], polling as long as needed,
+    /// see [`DotLockOptions::lock`].
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        DotLockOptions::new().lock(path)
+    }
+
+    /// Creates a `Self` instance with the default [`DotLockOptions`], polling until `timeout`
+    /// elapses, see [`DotLockOptions::lock_timeout`].
+    pub fn new_timeout<P: AsRef<Path>>(path: P, timeout: Duration) -> io::Result<Self> {
+        DotLockOptions::new().lock_timeout(path, timeout)
+    }
+
+    /// Returns the path of the lock file.
+    #[inline(always)]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the record written into the lock file.
+    #[inline(always)]
+    pub fn info(&self) -> &LockInfo {
+        &self.info
+    }
+
+    /// Sets the policy followed if removing the lock file fails at [dropping][`Drop`],
+    /// overriding the global one.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.policy = Some(policy);
+    }
+
+    /// Removes the lock file if it's still the one created by the guard.
+    fn release(&self) -> io::Result<()> {
+        let ours = FileId::of_path(&self.path)
+            .and_then(|id| Ok(id == FileId::of(&self.file)?))
+            .unwrap_or(false);
+
+        if !ours {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "dot lock broken by another owner",
+            ));
+        }
+
+        fs::remove_file(&self.path)
+    }
+
+    /// Removes the lock file returning any error that could have caused instead of reporting it
+    /// like [dropping][`Drop`] does.
+    pub fn unlock(self) -> io::Result<()> {
+        let this = ManuallyDrop::new(self);
+        let r = this.release();
+        // SAFETY: `this` is never dropped nor used again after moving its fields out.
+        unsafe {
+            drop((
+                ptr::read(&this.file),
+                ptr::read(&this.path),
+                ptr::read(&this.info),
+                ptr::read(&this.policy),
+            ))
+        };
+        r
+    }
+}
+
+impl Drop for DotLock {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            policy::report(self.policy.as_ref(), e, &self.file);
+        }
+    }
+}
+
+/// `.break` file removed at [dropping][`Drop`].
+struct BreakFile<'a>(&'a Path);
+
+impl Drop for BreakFile<'_> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.0);
+    }
+}
+
+fn contended(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::AlreadyExists => {
+            io::Error::new(io::ErrorKind::WouldBlock, "dot lock held by another owner")
+        }
+        _ => e,
+    }
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = OsString::from(path);
+    s.push(suffix);
+    s.into()
+}
+
+fn create_new(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(LOCK_FILE_MODE)
+        .open(path)
+}
+
+/// Creates a new file next to `path` named after it, the host, the process and a counter.
+fn create_unique(path: &Path) -> io::Result<(File, PathBuf)> {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    loop {
+        let unique = suffixed(
+            path,
+            &format!(
+                ".{}.{}.{}",
+                info::hostname().unwrap_or_default(),
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ),
+        );
+
+        match create_new(&unique) {
+            Ok(file) => return Ok((file, unique)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reads the identity and the record of the lock file at `path`, `None` if it's missing.
+fn read(path: &Path) -> io::Result<Option<(FileId, Option<LockInfo>)>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // A record being written or written by something else can't tell the owner.
+    let info = LockInfo::read_from(&file).ok().flatten();
+
+    Ok(Some((FileId::of(&file)?, info)))
+}
+
+/// Removes the lock file at `path` if it's still the one `found` was read from, returning
+/// whether it was.
+fn remove_unchanged(path: &Path, found: &(FileId, Option<LockInfo>)) -> io::Result<bool> {
+    if read(path)?.as_ref() != Some(found) {
+        return Ok(false);
+    }
+
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(true),
+    }
+}
+
+fn is_dead(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks whether the process exists.
+    let r = unsafe { libc::kill(pid as libc::pid_t, 0) };
+
+    r == -1 && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    /// Directory removed at dropping.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("raii_flock-dot-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir(&path).unwrap();
+            Self(path)
+        }
+
+        fn file(&self) -> PathBuf {
+            self.0.join("file")
+        }
+
+        fn lock_path(&self) -> PathBuf {
+            DotLock::lock_path(self.file())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn dead_pid() -> u32 {
+        let mut child = process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        pid
+    }
+
+    /// Writes a lock file at `path` owned by `pid` on this host.
+    fn write_lock(path: &Path, pid: u32) {
+        let mut record = format!("pid={}\ntime=0.0\n", pid);
+
+        if let Some(hostname) = info::hostname() {
+            record += &format!("hostname={}\n", hostname);
+        }
+
+        fs::write(path, record).unwrap();
+    }
+
+    #[test]
+    fn breaks_lock_of_dead_owner() {
+        let dir = TempDir::new("dead");
+        write_lock(&dir.lock_path(), dead_pid());
+
+        assert!(DotLockOptions::new().break_stale(&dir.lock_path()).unwrap());
+        assert!(!dir.lock_path().exists());
+    }
+
+    #[test]
+    fn keeps_lock_of_live_owner() {
+        let dir = TempDir::new("live");
+        write_lock(&dir.lock_path(), process::id());
+
+        assert!(!DotLockOptions::new().break_stale(&dir.lock_path()).unwrap());
+        assert!(dir.lock_path().exists());
+    }
+
+    #[test]
+    fn keeps_lock_of_dead_owner_unless_asked() {
+        let dir = TempDir::new("keep-dead");
+        write_lock(&dir.lock_path(), dead_pid());
+        let options = DotLockOptions::new().break_dead(false);
+
+        assert!(!options.break_stale(&dir.lock_path()).unwrap());
+        assert!(dir.lock_path().exists());
+    }
+
+    #[test]
+    fn breaks_old_lock() {
+        let dir = TempDir::new("old");
+        write_lock(&dir.lock_path(), process::id());
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(dir.lock_path())
+            .unwrap()
+            .set_modified(hour_ago)
+            .unwrap();
+        let options = DotLockOptions::new().stale_after(Duration::from_secs(60));
+
+        assert!(options.break_stale(&dir.lock_path()).unwrap());
+        assert!(!dir.lock_path().exists());
+    }
+
+    #[test]
+    fn keeps_lock_taken_again() {
+        let dir = TempDir::new("retaken");
+        write_lock(&dir.lock_path(), dead_pid());
+        let found = read(&dir.lock_path()).unwrap().unwrap();
+        let options = DotLockOptions::new();
+        assert!(options
+            .is_stale(&dir.lock_path(), found.1.as_ref())
+            .unwrap());
+
+        // Broken and taken again by someone else since it was found stale.
+        fs::remove_file(dir.lock_path()).unwrap();
+        let lock = options.try_lock(dir.file()).unwrap();
+
+        assert!(!remove_unchanged(&dir.lock_path(), &found).unwrap());
+        assert!(dir.lock_path().exists());
+        lock.unlock().unwrap();
+    }
+
+    #[test]
+    fn keeps_lock_being_broken_by_another() {
+        let dir = TempDir::new("breaking");
+        write_lock(&dir.lock_path(), dead_pid());
+        let break_path = suffixed(&dir.lock_path(), ".break");
+        fs::write(&break_path, "").unwrap();
+
+        assert!(!DotLockOptions::new().break_stale(&dir.lock_path()).unwrap());
+        assert!(dir.lock_path().exists() && break_path.exists());
+    }
+
+    #[test]
+    fn try_lock_takes_stale_lock() {
+        let dir = TempDir::new("take");
+        write_lock(&dir.lock_path(), dead_pid());
+
+        let lock = DotLockOptions::new().try_lock(dir.file()).unwrap();
+        assert_eq!(lock.info().pid(), process::id());
+        lock.unlock().unwrap();
+    }
+}
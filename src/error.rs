@@ -0,0 +1,50 @@
+use ::std::{error::Error, fmt, io};
+
+/// An I/O error paired with the value an operation would otherwise have lost when failing.
+#[derive(Debug)]
+pub struct LockError<T> {
+    value: T,
+    error: io::Error,
+}
+
+impl<T> LockError<T> {
+    pub(crate) fn new(value: T, error: io::Error) -> Self {
+        Self { value, error }
+    }
+
+    /// Returns the error that caused the failure.
+    #[inline(always)]
+    pub fn error(&self) -> &io::Error {
+        &self.error
+    }
+
+    /// Returns the value given back by the failed operation.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    /// Returns both the value given back by the failed operation and the error that caused it.
+    #[inline(always)]
+    pub fn into_parts(self) -> (T, io::Error) {
+        (self.value, self.error)
+    }
+}
+
+impl<T> fmt::Display for LockError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<T: fmt::Debug> Error for LockError<T> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl<T> From<LockError<T>> for io::Error {
+    fn from(e: LockError<T>) -> Self {
+        e.error
+    }
+}
@@ -0,0 +1,51 @@
+use ::std::{
+    fs::{self, File, Metadata},
+    io,
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+/// Identity of a file, the device it's in and its inode number, shared by every handle to it
+/// whatever path it was opened through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct FileId {
+    dev: u64,
+    ino: u64,
+}
+
+impl FileId {
+    /// Returns the identity of the file with the inode number `ino` in the device `dev`.
+    #[inline(always)]
+    pub(crate) fn new(dev: u64, ino: u64) -> Self {
+        Self { dev, ino }
+    }
+
+    fn from_metadata(metadata: &Metadata) -> Self {
+        Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        }
+    }
+
+    /// Returns the identity of the file `f` refers to.
+    pub(crate) fn of(f: &File) -> io::Result<Self> {
+        f.metadata().map(|metadata| Self::from_metadata(&metadata))
+    }
+
+    /// Returns the identity of the file `path` currently refers to, following symbolic links.
+    pub(crate) fn of_path(path: &Path) -> io::Result<Self> {
+        fs::metadata(path).map(|metadata| Self::from_metadata(&metadata))
+    }
+
+    /// Returns the device number of the file.
+    #[inline(always)]
+    pub(crate) fn dev(&self) -> u64 {
+        self.dev
+    }
+
+    /// Returns the inode number of the file.
+    #[inline(always)]
+    pub(crate) fn ino(&self) -> u64 {
+        self.ino
+    }
+}
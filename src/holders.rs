@@ -0,0 +1,108 @@
+use ::std::{convert::TryFrom, fs::File, io, process};
+
+use crate::{file_id::FileId, proc_locks, sys, LockMode};
+
+/// Kind of a lock held on a file, which only conflicts with locks of the same family.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LockKind {
+    /// Whole-file lock taken with `flock`, the kind the guards of this crate take.
+    Flock,
+    /// Classic POSIX record lock taken with `fcntl`, owned by a process.
+    Posix,
+    /// Open file description record lock taken with `fcntl`, like
+    /// [`RangeLock`][`crate::RangeLock`] does.
+    Ofd,
+}
+
+/// Lock held on a file by someone else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Holder {
+    pid: Option<u32>,
+    mode: LockMode,
+    kind: LockKind,
+}
+
+impl Holder {
+    /// Returns the process holding the lock, or the one that took it for the locks belonging to
+    /// open file descriptions, which could have been shared with others since.
+    ///
+    /// It's `None` for [`LockKind::Ofd`] locks, whose owner the kernel doesn't report.
+    #[inline(always)]
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    /// Returns the mode the lock is held in.
+    #[inline(always)]
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Returns the kind of the lock.
+    #[inline(always)]
+    pub fn kind(&self) -> LockKind {
+        self.kind
+    }
+}
+
+/// Returns every lock held on the file `f` refers to as listed in `/proc/locks`, including the
+/// ones held through `f` itself.
+pub fn holders(f: &File) -> io::Result<Vec<Holder>> {
+    let file = FileId::of(f)?;
+
+    Ok(proc_locks::read()?
+        .into_iter()
+        .filter(|entry| !entry.waiting && entry.file == file)
+        .map(|entry| Holder {
+            pid: entry.pid,
+            mode: entry.mode,
+            kind: entry.kind,
+        })
+        .collect())
+}
+
+/// Returns a lock held on the file `f` refers to that keeps it from being locked whole in `mode`,
+/// if any.
+///
+/// Record locks are looked for first with `F_OFD_GETLK`, which ignores the ones held through `f`
+/// itself. `flock` locks are looked for in `/proc/locks` after, which doesn't tell who holds them
+/// through which descriptor, so the ones held by other processes are preferred and a lock of the
+/// current process, possibly held through `f`, is only reported if there are none.
+pub fn conflicting_holder(f: &File, mode: LockMode) -> io::Result<Option<Holder>> {
+    match sys::getlk(f, libc::F_OFD_GETLK, mode, 0, 0) {
+        Ok(Some(lock)) => {
+            return Ok(Some(Holder {
+                pid: u32::try_from(lock.l_pid).ok(),
+                mode: if lock.l_type == libc::F_WRLCK as _ {
+                    LockMode::Exclusive
+                } else {
+                    LockMode::Shared
+                },
+                kind: if lock.l_pid == -1 {
+                    LockKind::Ofd
+                } else {
+                    LockKind::Posix
+                },
+            }))
+        }
+        Ok(None) => (),
+        // Kernels older than 3.15 lack open file description locks.
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => (),
+        Err(e) => return Err(e),
+    }
+
+    let conflicting: Vec<Holder> = holders(f)?
+        .into_iter()
+        .filter(|holder| {
+            holder.kind == LockKind::Flock
+                && (mode == LockMode::Exclusive || holder.mode == LockMode::Exclusive)
+        })
+        .collect();
+    let pid = Some(process::id());
+
+    Ok(conflicting
+        .iter()
+        .find(|holder| holder.pid != pid)
+        .or_else(|| conflicting.first())
+        .copied())
+}
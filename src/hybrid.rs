@@ -0,0 +1,309 @@
+use ::std::{
+    borrow::Borrow,
+    fs::{File, Metadata},
+    io::{self, prelude::*, SeekFrom},
+    ops::Deref,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    Acquisition, Converted, DropPolicy, Exclusive, LockError, LockMode, LockOptions, Mode,
+    OwnedFileLock, RegistryPolicy, RetryPolicy, Shared,
+};
+
+/// Guard excluding both other processes and the other threads of the process, which `flock`
+/// alone doesn't as its locks belong to the open file description threads may share.
+///
+/// It's an [`OwnedFileLock`] always taken through the registry with [`RegistryPolicy::Wait`],
+/// which works like a [`RwLock`][`std::sync::RwLock`] per file of the process on top of a single
+/// lock on the file shared by all its guards. Hybrid guards can't be converted between modes while
+/// other guards of the process share the lock.
+#[derive(Debug)]
+pub struct HybridLock<F: Borrow<File>, M: Mode> {
+    inner: OwnedFileLock<F, M>,
+}
+
+fn options() -> LockOptions {
+    LockOptions::new().registry(Some(RegistryPolicy::Wait))
+}
+
+impl<F: Borrow<File>, M: Mode> HybridLock<F, M> {
+    #[inline(always)]
+    fn map<N: Mode>(
+        r: io::Result<Converted<OwnedFileLock<F, N>>>,
+    ) -> io::Result<Converted<HybridLock<F, N>>> {
+        r.map(|c| Converted {
+            guard: HybridLock { inner: c.guard },
+            atomic: c.atomic,
+        })
+    }
+
+    /// Returns the mode the lock is held in, which is always `M::MODE`.
+    #[inline(always)]
+    pub fn mode(&self) -> LockMode {
+        self.inner.mode()
+    }
+
+    /// Returns how the lock was acquired.
+    #[inline(always)]
+    pub fn acquisition(&self) -> Acquisition {
+        self.inner.acquisition()
+    }
+
+    /// Returns when the lock was acquired, or last converted between modes.
+    #[inline(always)]
+    pub fn acquired_at(&self) -> Instant {
+        self.inner.acquired_at()
+    }
+
+    /// Returns whether the guard lost its lock like [`OwnedFileLock::lost`] does.
+    #[inline(always)]
+    pub fn lost(&self) -> bool {
+        self.inner.lost()
+    }
+
+    /// Sets the policy followed if unlocking fails at [dropping][`Drop`], overriding the global
+    /// one.
+    #[inline(always)]
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.inner.set_drop_policy(policy)
+    }
+
+    /// Queries metadata about the underlying file with [`File::metadata`].
+    #[inline(always)]
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        self.inner.metadata()
+    }
+
+    /// Unlocks the file the same way [dropping][`Drop`] does and gives the wrapped value back.
+    #[inline(always)]
+    pub fn into_inner(self) -> F {
+        self.inner.into_inner()
+    }
+
+    /// Unlocks the file and gives the wrapped value back, returning it along with the error
+    /// instead of reporting it like [dropping][`Drop`] does if unlocking fails.
+    #[inline(always)]
+    pub fn try_into_inner(self) -> Result<F, LockError<F>> {
+        self.inner.try_into_inner()
+    }
+
+    /// Unlocks the file returning any error that could have caused instead of reporting it like
+    /// [dropping][`Drop`] does.
+    #[inline(always)]
+    pub fn unlock(self) -> io::Result<()> {
+        self.inner.unlock()
+    }
+}
+
+impl<F: Borrow<File>> HybridLock<F, Shared> {
+    /// Creates a `Self` instance locking `f` shared if neither other processes nor other threads
+    /// hold it exclusive and returning any error that could have caused.
+    pub fn try_wrap_shared(f: F) -> io::Result<Self> {
+        options().try_wrap(f).map(|inner| Self { inner })
+    }
+
+    /// Creates a `Self` instance locking `f` shared, waiting for other processes and threads as
+    /// long as needed, and returning any error that could have caused.
+    pub fn wrap_shared(f: F) -> io::Result<Self> {
+        options().wrap(f).map(|inner| Self { inner })
+    }
+
+    /// Creates a `Self` instance waiting until `f` can be locked shared or `timeout` elapses,
+    /// returning an error of kind [`io::ErrorKind::TimedOut`] in the latter case or any other
+    /// error that could have caused.
+    pub fn wrap_shared_timeout(f: F, timeout: Duration) -> io::Result<Self> {
+        options()
+            .wrap_timeout(f, timeout)
+            .map(|inner| Self { inner })
+    }
+
+    /// Creates a `Self` instance waiting until `f` can be locked shared or `deadline` is reached,
+    /// returning an error of kind [`io::ErrorKind::TimedOut`] in the latter case or any other
+    /// error that could have caused.
+    pub fn wrap_shared_deadline(f: F, deadline: Instant) -> io::Result<Self> {
+        options()
+            .wrap_deadline(f, deadline)
+            .map(|inner| Self { inner })
+    }
+
+    /// Creates a `Self` instance waiting until `f` can be locked shared following `policy` and
+    /// returning any error that could have caused, see [`RetryPolicy`] for the ones it gives up
+    /// with.
+    pub fn wrap_shared_with_retry(f: F, policy: &RetryPolicy) -> io::Result<Self> {
+        options()
+            .wrap_with_retry(f, policy)
+            .map(|inner| Self { inner })
+    }
+
+    /// Converts the lock to exclusive like [`OwnedFileLock::upgrade`] does, failing with an error
+    /// of kind [`io::ErrorKind::Deadlock`] if other guards of the process share it.
+    pub fn upgrade(self) -> io::Result<Converted<HybridLock<F, Exclusive>>> {
+        Self::map(self.inner.upgrade())
+    }
+
+    /// Converts the lock to exclusive like [`OwnedFileLock::try_upgrade`] does, failing with an
+    /// error of kind [`io::ErrorKind::Deadlock`] if other guards of the process share it.
+    pub fn try_upgrade(self) -> Result<Converted<HybridLock<F, Exclusive>>, LockError<Self>> {
+        match self.inner.try_upgrade() {
+            Ok(c) => Ok(Converted {
+                guard: HybridLock { inner: c.guard },
+                atomic: c.atomic,
+            }),
+            Err(e) => {
+                let (inner, e) = e.into_parts();
+                Err(LockError::new(Self { inner }, e))
+            }
+        }
+    }
+}
+
+impl<F: Borrow<File>> HybridLock<F, Exclusive> {
+    /// Creates a `Self` instance locking `f` exclusive if neither other processes nor other
+    /// threads hold it and returning any error that could have caused.
+    pub fn try_wrap_exclusive(f: F) -> io::Result<Self> {
+        options().try_wrap(f).map(|inner| Self { inner })
+    }
+
+    /// Creates a `Self` instance locking `f` exclusive, waiting for other processes and threads
+    /// as long as needed, and returning any error that could have caused.
+    pub fn wrap_exclusive(f: F) -> io::Result<Self> {
+        options().wrap(f).map(|inner| Self { inner })
+    }
+
+    /// Creates a `Self` instance waiting until `f` can be locked exclusive or `timeout` elapses,
+    /// returning an error of kind [`io::ErrorKind::TimedOut`] in the latter case or any other
+    /// error that could have caused.
+    pub fn wrap_exclusive_timeout(f: F, timeout: Duration) -> io::Result<Self> {
+        options()
+            .wrap_timeout(f, timeout)
+            .map(|inner| Self { inner })
+    }
+
+    /// Creates a `Self` instance waiting until `f` can be locked exclusive or `deadline` is
+    /// reached, returning an error of kind [`io::ErrorKind::TimedOut`] in the latter case or any
+    /// other error that could have caused.
+    pub fn wrap_exclusive_deadline(f: F, deadline: Instant) -> io::Result<Self> {
+        options()
+            .wrap_deadline(f, deadline)
+            .map(|inner| Self { inner })
+    }
+
+    /// Creates a `Self` instance waiting until `f` can be locked exclusive following `policy` and
+    /// returning any error that could have caused, see [`RetryPolicy`] for the ones it gives up
+    /// with.
+    pub fn wrap_exclusive_with_retry(f: F, policy: &RetryPolicy) -> io::Result<Self> {
+        options()
+            .wrap_with_retry(f, policy)
+            .map(|inner| Self { inner })
+    }
+
+    /// Converts the lock to shared like [`OwnedFileLock::downgrade`] does, failing with an error
+    /// of kind [`io::ErrorKind::Deadlock`] if other guards of the process share it.
+    pub fn downgrade(self) -> io::Result<Converted<HybridLock<F, Shared>>> {
+        Self::map(self.inner.downgrade())
+    }
+
+    /// Returns a reference to the wrapped value.
+    #[inline(always)]
+    pub fn get_ref(&self) -> &F {
+        self.inner.get_ref()
+    }
+}
+
+impl<F: Borrow<File>> Write for HybridLock<F, Exclusive> {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<F: Borrow<File>, M: Mode> Read for HybridLock<F, M> {
+    #[inline(always)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<F: Borrow<File>, M: Mode> Seek for HybridLock<F, M> {
+    #[inline(always)]
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<F: Borrow<File>> Deref for HybridLock<F, Exclusive> {
+    type Target = File;
+
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::open_lock_file;
+    use std::{
+        env, fs,
+        path::{Path, PathBuf},
+        process,
+        sync::mpsc,
+        thread,
+    };
+
+    fn path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("raii_flock-hybrid-{}-{}", name, process::id()))
+    }
+
+    /// Runs `f` on another thread while this one holds the file at `path` exclusive.
+    fn while_held<T: Send + 'static>(path: &Path, f: impl FnOnce(File) -> T + Send + 'static) -> T {
+        let held = HybridLock::wrap_exclusive(open_lock_file(path, 0o600).unwrap()).unwrap();
+        let other = open_lock_file(path, 0o600).unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || sender.send(f(other)).unwrap());
+        let r = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        drop(held);
+        let _ = fs::remove_file(path);
+        r
+    }
+
+    #[test]
+    fn retry_gives_up_after_attempts_while_thread_holds_file() {
+        let policy = RetryPolicy::fixed(Duration::from_millis(1)).max_attempts(3);
+
+        let kinds = while_held(&path("attempts"), move |f| {
+            let shared = HybridLock::wrap_shared_with_retry(&f, &policy).map(drop);
+            let exclusive = HybridLock::wrap_exclusive_with_retry(&f, &policy).map(drop);
+            (shared.unwrap_err().kind(), exclusive.unwrap_err().kind())
+        });
+
+        assert_eq!(
+            kinds,
+            (io::ErrorKind::WouldBlock, io::ErrorKind::WouldBlock)
+        );
+    }
+
+    #[test]
+    fn retry_gives_up_after_elapsed_while_thread_holds_file() {
+        let elapsed = Duration::from_millis(100);
+        let policy = RetryPolicy::fixed(Duration::from_millis(1)).max_elapsed(elapsed);
+
+        let (kind, waited) = while_held(&path("elapsed"), move |f| {
+            let start = Instant::now();
+            let r = HybridLock::wrap_exclusive_with_retry(&f, &policy).map(drop);
+            (r.unwrap_err().kind(), start.elapsed())
+        });
+
+        assert_eq!(kind, io::ErrorKind::TimedOut);
+        assert!(waited >= elapsed && waited < elapsed * 2, "{:?}", waited);
+    }
+}
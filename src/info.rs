@@ -0,0 +1,365 @@
+use ::std::{
+    env, fmt,
+    fs::{self, File},
+    io::{self, prelude::*, SeekFrom},
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Record of who holds a lock, written into the lock file by exclusive guards created with
+/// [`LockOptions::info`][`crate::LockOptions::info`] for contending processes to read it with
+/// [`LockInfo::read`].
+///
+/// It's stored as `key=value` lines, with backslashes and line breaks in values escaped, so it's
+/// only meant for lock files holding nothing else.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockInfo {
+    pid: u32,
+    hostname: Option<String>,
+    executable: Option<PathBuf>,
+    command_line: Vec<String>,
+    acquired_at: SystemTime,
+    message: Option<String>,
+}
+
+impl LockInfo {
+    /// Creates a record describing the current process, acquiring the lock now.
+    pub fn current() -> Self {
+        Self {
+            pid: process::id(),
+            hostname: hostname(),
+            executable: env::current_exe().ok(),
+            command_line: env::args_os()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            acquired_at: SystemTime::now(),
+            message: None,
+        }
+    }
+
+    /// Sets a message telling what the lock is held for.
+    pub fn with_message<S: Into<String>>(mut self, message: S) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    /// Returns the PID of the process holding the lock.
+    #[inline(always)]
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Returns the name of the host the process runs on, if it could be found.
+    #[inline(always)]
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+
+    /// Returns the path of the executable of the process, if it could be found.
+    #[inline(always)]
+    pub fn executable(&self) -> Option<&Path> {
+        self.executable.as_deref()
+    }
+
+    /// Returns the arguments the process was started with, including the program name.
+    #[inline(always)]
+    pub fn command_line(&self) -> &[String] {
+        &self.command_line
+    }
+
+    /// Returns when the lock was acquired.
+    #[inline(always)]
+    pub fn acquired_at(&self) -> SystemTime {
+        self.acquired_at
+    }
+
+    /// Returns the message telling what the lock is held for.
+    #[inline(always)]
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    /// Returns a copy of `self` recording the lock as acquired now.
+    pub(crate) fn now(&self) -> Self {
+        Self {
+            acquired_at: SystemTime::now(),
+            ..self.clone()
+        }
+    }
+
+    /// Replaces the contents of `f` with the record and returns any error that could have caused.
+    pub fn write_to(&self, mut f: &File) -> io::Result<()> {
+        let time = self
+            .acquired_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut s = format!(
+            "pid={}\ntime={}.{:09}\n",
+            self.pid,
+            time.as_secs(),
+            time.subsec_nanos()
+        );
+
+        if let Some(hostname) = &self.hostname {
+            s += &format!("hostname={}\n", escape(hostname));
+        }
+
+        if let Some(executable) = &self.executable {
+            s += &format!("executable={}\n", escape(&executable.to_string_lossy()));
+        }
+
+        for arg in &self.command_line {
+            s += &format!("arg={}\n", escape(arg));
+        }
+
+        if let Some(message) = &self.message {
+            s += &format!("message={}\n", escape(message));
+        }
+
+        f.set_len(0)?;
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(s.as_bytes())?;
+        f.flush()
+    }
+
+    /// Reads the record from `f`, returning `None` if it's empty or an error of kind
+    /// [`io::ErrorKind::InvalidData`] if it doesn't hold one.
+    pub fn read_from(mut f: &File) -> io::Result<Option<Self>> {
+        let mut s = String::new();
+        f.seek(SeekFrom::Start(0))?;
+        f.read_to_string(&mut s)?;
+
+        parse(&s)
+    }
+
+    /// Reads the record from the file at `path` like [`LockInfo::read_from`] does.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Option<Self>> {
+        parse(&fs::read_to_string(path)?)
+    }
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pid {}", self.pid)?;
+
+        if let Some(name) = self.executable.as_ref().and_then(|e| e.file_name()) {
+            write!(f, " ({})", name.to_string_lossy())?;
+        }
+
+        if let Some(hostname) = &self.hostname {
+            write!(f, " on {}", hostname)?;
+        }
+
+        f.write_str(" since ")?;
+        write_utc(f, self.acquired_at)?;
+
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes `time` as a UTC date and time like `2024-05-17 10:02:41 UTC`.
+fn write_utc(f: &mut fmt::Formatter<'_>, time: SystemTime) -> fmt::Result {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since the epoch, counting years from March so leap days come last.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    write!(
+        f,
+        "{}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3_600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid lock info")
+}
+
+fn parse(s: &str) -> io::Result<Option<LockInfo>> {
+    if s.trim().is_empty() {
+        return Ok(None);
+    }
+
+    let mut pid = None;
+    let mut info = LockInfo {
+        pid: 0,
+        hostname: None,
+        executable: None,
+        command_line: Vec::new(),
+        acquired_at: UNIX_EPOCH,
+        message: None,
+    };
+
+    for line in s.lines() {
+        let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+
+        match key {
+            "pid" => pid = Some(value.parse().map_err(|_| invalid())?),
+            "time" => {
+                let (secs, nanos) = value.split_once('.').unwrap_or((value, "0"));
+                let nanos = nanos.parse().map_err(|_| invalid())?;
+
+                if nanos >= 1_000_000_000 {
+                    return Err(invalid());
+                }
+
+                let time = Duration::new(secs.parse().map_err(|_| invalid())?, nanos);
+                info.acquired_at = UNIX_EPOCH.checked_add(time).ok_or_else(invalid)?;
+            }
+            "hostname" => info.hostname = Some(unescape(value)),
+            "executable" => info.executable = Some(unescape(value).into()),
+            "arg" => info.command_line.push(unescape(value)),
+            "message" => info.message = Some(unescape(value)),
+            // Written by newer versions.
+            _ => (),
+        }
+    }
+
+    info.pid = pid.ok_or_else(invalid)?;
+    Ok(Some(info))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+#[cfg(unix)]
+pub(crate) fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+
+    // SAFETY: the buffer is valid for writes of its whole length.
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn hostname() -> Option<String> {
+    env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> LockInfo {
+        LockInfo {
+            pid: 1234,
+            hostname: Some("build-01".to_owned()),
+            executable: Some("/usr/local/bin/deploy.sh".into()),
+            command_line: vec!["deploy.sh".to_owned(), "--env\nprod".to_owned()],
+            acquired_at: UNIX_EPOCH + Duration::new(1_715_940_161, 123_456_789),
+            message: Some("migrating C:\\data\r\n".to_owned()),
+        }
+    }
+
+    #[test]
+    fn escape_round_trips() {
+        for s in ["", "plain", "a\\b", "line\nbreak\r\n", "\\n", "trailing\\"] {
+            let escaped = escape(s);
+            assert!(!escaped.contains('\n') && !escaped.contains('\r'));
+            assert_eq!(unescape(&escaped), s);
+        }
+    }
+
+    #[test]
+    fn unescape_keeps_unknown_escapes() {
+        assert_eq!(unescape("\\t\\"), "t\\");
+    }
+
+    #[test]
+    fn parse_round_trips() {
+        let info = info();
+        let path = std::env::temp_dir().join(format!("raii_flock-info-{}", process::id()));
+        let f = crate::open_lock_file(&path, 0o600).unwrap();
+
+        info.write_to(&f).unwrap();
+        let read = LockInfo::read_from(&f);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.unwrap(), Some(info));
+    }
+
+    #[test]
+    fn parse_skips_empty_records_and_unknown_keys() {
+        assert_eq!(parse(" \n").unwrap(), None);
+
+        let info = parse("pid=7\nfuture=1\n").unwrap().unwrap();
+        assert_eq!((info.pid(), info.acquired_at()), (7, UNIX_EPOCH));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_records() {
+        for s in [
+            "time=1.0\n",
+            "pid=x\n",
+            "pid\n",
+            "pid=1\ntime=1.1000000000\n",
+            "pid=1\ntime=x.0\n",
+        ] {
+            assert_eq!(
+                parse(s).unwrap_err().kind(),
+                io::ErrorKind::InvalidData,
+                "{:?}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn display_shows_the_acquisition_time() {
+        assert_eq!(
+            info().to_string(),
+            "pid 1234 (deploy.sh) on build-01 since 2024-05-17 10:02:41 UTC: migrating C:\\data\r\n"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use ::std::{
+    error::Error,
+    fmt,
+    fs::{self, File},
+    io::{self, prelude::*},
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{acquire, file_id::FileId, open_lock_file, Exclusive, OwnedFileLock, LOCK_FILE_MODE};
+
+/// Guard keeping a single instance of a program running at once through a pidfile.
+///
+/// The pidfile is locked exclusive with [`OwnedFileLock::try_wrap_exclusive`] and holds the PID
+/// of the instance and when it started, as decimal seconds since the Unix epoch, on a line each.
+/// It's removed at [dropping][`Drop`] while still locked, or emptied if it can't be removed.
+#[derive(Debug)]
+pub struct SingleInstance {
+    lock: OwnedFileLock<File, Exclusive>,
+    path: PathBuf,
+    started_at: SystemTime,
+}
+
+/// Payload of the error of kind [`io::ErrorKind::WouldBlock`] [`SingleInstance::new`] fails with
+/// when another instance is running, telling which if its pidfile could be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AlreadyRunning {
+    pid: Option<u32>,
+    started_at: Option<SystemTime>,
+}
+
+impl AlreadyRunning {
+    /// Returns the PID of the running instance.
+    #[inline(always)]
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    /// Returns when the running instance started.
+    #[inline(always)]
+    pub fn started_at(&self) -> Option<SystemTime> {
+        self.started_at
+    }
+
+    /// Returns the payload of `e` if it's the error another instance running was reported with.
+    pub fn from_error(e: &io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for AlreadyRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "another instance is already running with pid {}", pid),
+            None => f.write_str("another instance is already running"),
+        }
+    }
+}
+
+impl Error for AlreadyRunning {}
+
+impl SingleInstance {
+    /// Creates a `Self` instance locking the pidfile at `path`, creating it if needed, and
+    /// writing the PID of the process and the current time into it, returning an error with an
+    /// [`AlreadyRunning`] payload if another instance holds it or any other error that could have
+    /// caused.
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        let mut lock = loop {
+            let file = open_lock_file(path, LOCK_FILE_MODE)?;
+            let lock = match OwnedFileLock::try_wrap_exclusive(file) {
+                Ok(lock) => lock,
+                Err(e) if acquire::is_contended(&e) => {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, read(path)))
+                }
+                Err(e) => return Err(e),
+            };
+
+            match FileId::of_path(path) {
+                Ok(id) if id == FileId::of(&lock)? => break lock,
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                // Removed by the instance that held it before it unlocked it.
+                _ => (),
+            }
+        };
+        let started_at = SystemTime::now();
+        let secs = started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        lock.set_len(0)?;
+        write!(lock, "{}\n{}\n", process::id(), secs)?;
+
+        Ok(Self {
+            lock,
+            path: path.to_owned(),
+            started_at,
+        })
+    }
+
+    /// Returns the path of the pidfile.
+    #[inline(always)]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns when the instance started, as written into the pidfile.
+    #[inline(always)]
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+}
+
+impl Drop for SingleInstance {
+    fn drop(&mut self) {
+        if fs::remove_file(&self.path).is_err() {
+            let _ = self.lock.set_len(0);
+        }
+    }
+}
+
+/// Reads the pidfile of the running instance, giving up on whatever can't be parsed.
+fn read(path: &Path) -> AlreadyRunning {
+    let contents = fs::read_to_string(path).unwrap_or_default();
+    let mut lines = contents.lines();
+
+    AlreadyRunning {
+        pid: lines.next().and_then(|pid| pid.trim().parse().ok()),
+        started_at: lines
+            .next()
+            .and_then(|secs| secs.trim().parse().ok())
+            .and_then(|secs| UNIX_EPOCH.checked_add(Duration::from_secs(secs))),
+    }
+}
@@ -2,99 +2,569 @@
 //! it goes out of scope.
 
 use ::{
-        fs2::FileExt,
         std::{
-            fs::File,
+            fs::{File, OpenOptions},
             io::{self, SeekFrom, prelude::*},
+            marker::PhantomData,
+            mem::ManuallyDrop,
             ops::{Deref, DerefMut},
-            thread::panicking,
+            path::Path,
+            ptr,
+            time::{Duration, Instant},
         },
 };
 
+use acquire::Wait;
+#[cfg(doc)]
+use fs2::FileExt;
+#[cfg(unix)]
+use {deadlock::Held, registry::Registration};
+
+mod acquire;
+#[cfg(feature = "tokio")]
+mod async_lock;
+#[cfg(unix)]
+mod atomic;
+mod backend;
+mod cancel;
+#[cfg(unix)]
+mod deadlock;
+#[cfg(unix)]
+mod dir;
+#[cfg(unix)]
+mod dot;
+mod error;
+#[cfg(unix)]
+mod file_id;
+#[cfg(target_os = "linux")]
+mod holders;
+#[cfg(unix)]
+mod hybrid;
+mod info;
+#[cfg(unix)]
+mod instance;
+#[cfg(unix)]
+mod lock_set;
+mod mode;
+mod options;
+mod owned;
+mod policy;
+#[cfg(target_os = "linux")]
+mod proc_locks;
+#[cfg(target_os = "linux")]
+mod range;
+#[cfg(unix)]
+mod registry;
+mod retry;
+#[cfg(unix)]
+mod sys;
+#[cfg(target_os = "linux")]
+mod wait_graph;
+
+#[cfg(feature = "tokio")]
+pub use async_lock::AsyncFileLock;
+#[cfg(unix)]
+pub use {
+    atomic::AtomicWriter,
+    backend::PosixFcntl,
+    deadlock::{
+        deadlock_detection, set_deadlock_detection, BlockedThread, DeadlockCycle,
+        DeadlockDetection,
+    },
+    dir::{DirLock, DirLockOptions},
+    dot::{DotLock, DotLockOptions},
+    hybrid::HybridLock,
+    instance::{AlreadyRunning, SingleInstance},
+    lock_set::{LockSet, LockSetBuilder},
+    registry::{registry_policy, set_registry_policy, RegistryPolicy},
+};
+#[cfg(target_os = "linux")]
+pub use {
+    backend::OfdFcntl,
+    holders::{conflicting_holder, holders, Holder, LockKind},
+    range::RangeLock,
+    wait_graph::{process_deadlocks, LockWait, ProcessDeadlock, WaitGraph},
+};
+pub use {
+    acquire::{Acquisition, LockMode},
+    backend::{Flock, LockBackend, StdLock},
+    cancel::{CancelToken, Cancellation, Cancelled},
+    error::LockError,
+    info::LockInfo,
+    mode::{Exclusive, Mode, Shared},
+    options::LockOptions,
+    owned::OwnedFileLock,
+    policy::{drop_policy, set_drop_policy, DropCallback, DropPolicy},
+    retry::RetryPolicy,
+};
+
 /// Wrapper over a file that calls [`FileExt::unlock`] at [dropping][`Drop`].
+///
+/// `M` is either [`Shared`] or [`Exclusive`], only the latter implementing [`Write`] and giving
+/// access to the file.
 #[derive(Debug)]
-pub struct FileLock<'a>(pub &'a File);
+pub struct FileLock<'a, M: Mode> {
+    file: &'a File,
+    state: LockState,
+    mode: PhantomData<M>,
+}
+
+impl<'a, M: Mode> FileLock<'a, M> {
+    fn new(file: &'a File, wait: Wait) -> io::Result<Self> {
+        Ok(Self {
+            file,
+            state: LockOptions::new().lock(file, M::MODE, wait)?,
+            mode: PhantomData,
+        })
+    }
+
+    fn into_parts(self) -> (&'a File, LockState) {
+        let this = ManuallyDrop::new(self);
+        // SAFETY: `this` is never dropped nor used again after moving its state out.
+        (this.file, unsafe { ptr::read(&this.state) })
+    }
+
+    fn cast<N: Mode>(self) -> FileLock<'a, N> {
+        let (file, state) = self.into_parts();
+
+        FileLock {
+            file,
+            state,
+            mode: PhantomData,
+        }
+    }
 
-impl<'a> FileLock<'a> {
+    /// Unlocks the file returning any error that could have caused instead of reporting it like
+    /// [dropping][`Drop`] does.
+    pub fn unlock(self) -> io::Result<()> {
+        let (file, state) = self.into_parts();
+        state.unlock(file)
+    }
+
+    /// Returns the mode the lock is held in, which is always `M::MODE`.
+    #[inline(always)]
+    pub fn mode(&self) -> LockMode {
+        self.state.mode
+    }
+
+    /// Returns how the lock was acquired.
+    #[inline(always)]
+    pub fn acquisition(&self) -> Acquisition {
+        self.state.acquisition
+    }
+
+    /// Returns when the lock was acquired, or last converted between modes.
+    #[inline(always)]
+    pub fn acquired_at(&self) -> Instant {
+        self.state.acquired_at
+    }
+
+    /// Returns whether the guard lost its lock, as a failed non-atomic conversion may release it
+    /// without being able to take it again, in which case it should be dropped.
+    #[inline(always)]
+    pub fn lost(&self) -> bool {
+        self.state.lost
+    }
+
+    /// Sets the policy followed if unlocking fails at [dropping][`Drop`], overriding the global
+    /// one.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.state.policy = Some(policy);
+    }
+}
+
+impl<'a> FileLock<'a, Shared> {
     /// Creates a `Self` instance calling [`FileExt::try_lock_shared`] on `f` and returning any
     /// error that could have caused.
     pub fn try_wrap_shared(f: &'a File) -> io::Result<Self> {
-        f.try_lock_shared()?;
-        Ok(Self(f))
+        Self::new(f, Wait::No)
     }
 
-    /// Creates a `Self` instance calling [`FileExt::lock_shared`] on `f` and returning any
-    /// error that could have caused.
+    /// Creates a `Self` instance calling [`FileExt::lock_shared`] on `f`, again if interrupted by a
+    /// signal, and returning any error that could have caused.
     pub fn wrap_shared(f: &'a File) -> io::Result<Self> {
-        f.lock_shared()?;
-        Ok(Self(f))
+        Self::new(f, Wait::Blocking)
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_shared`] on `f` until it succeeds
+    /// or `timeout` elapses, returning an error of kind [`io::ErrorKind::TimedOut`] in the latter
+    /// case or any other error that could have caused.
+    pub fn wrap_shared_timeout(f: &'a File, timeout: Duration) -> io::Result<Self> {
+        Self::new(f, Wait::timeout(timeout))
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_shared`] on `f` until it succeeds
+    /// or `deadline` is reached, returning an error of kind [`io::ErrorKind::TimedOut`] in the
+    /// latter case or any other error that could have caused.
+    pub fn wrap_shared_deadline(f: &'a File, deadline: Instant) -> io::Result<Self> {
+        Self::new(f, Wait::deadline(deadline))
     }
 
+    /// Creates a `Self` instance polling [`FileExt::try_lock_shared`] on `f` following `policy`
+    /// and returning any error that could have caused, see [`RetryPolicy`] for the ones it gives
+    /// up with.
+    pub fn wrap_shared_with_retry(f: &'a File, policy: &RetryPolicy) -> io::Result<Self> {
+        Self::new(f, Wait::Retry(policy.clone(), None))
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_shared`] on `f` until it succeeds,
+    /// giving up with an error with a [`Cancelled`] payload as soon as `cancel` is signalled, and
+    /// returning any other error that could have caused.
+    pub fn wrap_shared_cancellable<C: Cancellation + ?Sized>(
+        f: &'a File,
+        cancel: &C,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            file: f,
+            state: LockOptions::new().lock_cancellable(f, LockMode::Shared, cancel)?,
+            mode: PhantomData,
+        })
+    }
+
+    /// Converts the lock to exclusive, waiting for it if needed, and returns any error that could
+    /// have caused.
+    ///
+    /// Other processes may take the lock while converting if the conversion isn't
+    /// [atomic][`Converted::atomic`], so anything read before should be read again.
+    pub fn upgrade(mut self) -> io::Result<Converted<FileLock<'a, Exclusive>>> {
+        let atomic = self.state.convert(self.file, LockMode::Exclusive, true)?;
+
+        Ok(Converted {
+            guard: self.cast(),
+            atomic,
+        })
+    }
+
+    /// Converts the lock to exclusive if it can be done without waiting, giving `self` back with
+    /// the error that caused the conversion to fail otherwise.
+    ///
+    /// If the conversion isn't [atomic][`Converted::atomic`] the lock is released first and taken
+    /// shared again if that can be done without waiting, `self` being given back having
+    /// [lost][`FileLock::lost`] it otherwise as someone else took it meanwhile.
+    pub fn try_upgrade(mut self) -> Result<Converted<FileLock<'a, Exclusive>>, LockError<Self>> {
+        match self.state.convert(self.file, LockMode::Exclusive, false) {
+            Ok(atomic) => Ok(Converted {
+                guard: self.cast(),
+                atomic,
+            }),
+            Err(e) => Err(LockError::new(self, e)),
+        }
+    }
+}
+
+impl<'a> FileLock<'a, Exclusive> {
     /// Creates a `Self` instance calling [`FileExt::try_lock_exclusive`] on `f` and returning any
     /// error that could have caused.
     pub fn try_wrap_exclusive(f: &'a File) -> io::Result<Self> {
-        f.try_lock_exclusive()?;
-        Ok(Self(f))
+        Self::new(f, Wait::No)
     }
 
-    /// Creates a `Self` instance calling [`FileExt::lock_exclusive`] on `f` and returning any
-    /// error that could have caused.
+    /// Creates a `Self` instance calling [`FileExt::lock_exclusive`] on `f`, again if interrupted
+    /// by a signal, and returning any error that could have caused.
     pub fn wrap_exclusive(f: &'a File) -> io::Result<Self> {
-        f.lock_exclusive()?;
-        Ok(Self(f))
+        Self::new(f, Wait::Blocking)
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_exclusive`] on `f` until it succeeds
+    /// or `timeout` elapses, returning an error of kind [`io::ErrorKind::TimedOut`] in the latter
+    /// case or any other error that could have caused.
+    pub fn wrap_exclusive_timeout(f: &'a File, timeout: Duration) -> io::Result<Self> {
+        Self::new(f, Wait::timeout(timeout))
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_exclusive`] on `f` until it succeeds
+    /// or `deadline` is reached, returning an error of kind [`io::ErrorKind::TimedOut`] in the
+    /// latter case or any other error that could have caused.
+    pub fn wrap_exclusive_deadline(f: &'a File, deadline: Instant) -> io::Result<Self> {
+        Self::new(f, Wait::deadline(deadline))
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_exclusive`] on `f` following `policy`
+    /// and returning any error that could have caused, see [`RetryPolicy`] for the ones it gives
+    /// up with.
+    pub fn wrap_exclusive_with_retry(f: &'a File, policy: &RetryPolicy) -> io::Result<Self> {
+        Self::new(f, Wait::Retry(policy.clone(), None))
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_exclusive`] on `f` until it succeeds,
+    /// giving up with an error with a [`Cancelled`] payload as soon as `cancel` is signalled, and
+    /// returning any other error that could have caused.
+    pub fn wrap_exclusive_cancellable<C: Cancellation + ?Sized>(
+        f: &'a File,
+        cancel: &C,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            file: f,
+            state: LockOptions::new().lock_cancellable(f, LockMode::Exclusive, cancel)?,
+            mode: PhantomData,
+        })
+    }
+
+    /// Converts the lock to shared and returns any error that could have caused.
+    pub fn downgrade(mut self) -> io::Result<Converted<FileLock<'a, Shared>>> {
+        let atomic = self.state.convert(self.file, LockMode::Shared, true)?;
+
+        Ok(Converted {
+            guard: self.cast(),
+            atomic,
+        })
+    }
+}
+
+impl FileLock<'_, Shared> {
+    /// Opens `path` with [`open_lock_file`] using [`LOCK_FILE_MODE`] and wraps it in an
+    /// [`OwnedFileLock`] with [`OwnedFileLock::try_wrap_shared`], see [`LockOptions::try_open`]
+    /// for other permissions.
+    ///
+    /// If the file exists but can't be opened for writing it's opened read-only instead.
+    pub fn try_open_shared<P: AsRef<Path>>(path: P) -> io::Result<OwnedFileLock<File, Shared>> {
+        LockOptions::new().try_open(path)
+    }
+
+    /// Opens `path` with [`open_lock_file`] using [`LOCK_FILE_MODE`] and wraps it in an
+    /// [`OwnedFileLock`] with [`OwnedFileLock::wrap_shared`], see [`LockOptions::open`] for other
+    /// permissions.
+    ///
+    /// If the file exists but can't be opened for writing it's opened read-only instead.
+    pub fn open_shared<P: AsRef<Path>>(path: P) -> io::Result<OwnedFileLock<File, Shared>> {
+        LockOptions::new().open(path)
     }
 }
 
-impl<'a> Write for FileLock<'a> {
+impl FileLock<'_, Exclusive> {
+    /// Opens `path` with [`open_lock_file`] using [`LOCK_FILE_MODE`] and wraps it in an
+    /// [`OwnedFileLock`] with [`OwnedFileLock::try_wrap_exclusive`], see [`LockOptions::try_open`]
+    /// for other permissions.
+    pub fn try_open_exclusive<P: AsRef<Path>>(
+        path: P,
+    ) -> io::Result<OwnedFileLock<File, Exclusive>> {
+        LockOptions::new().try_open(path)
+    }
+
+    /// Opens `path` with [`open_lock_file`] using [`LOCK_FILE_MODE`] and wraps it in an
+    /// [`OwnedFileLock`] with [`OwnedFileLock::wrap_exclusive`], see [`LockOptions::open`] for
+    /// other permissions.
+    pub fn open_exclusive<P: AsRef<Path>>(path: P) -> io::Result<OwnedFileLock<File, Exclusive>> {
+        LockOptions::new().open(path)
+    }
+}
+
+impl<'a> Write for FileLock<'a, Exclusive> {
     #[inline(always)]
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        self.file.write(buf)
     }
 
     #[inline(always)]
     fn flush(&mut self) -> io::Result<()> {
-        self.0.flush()
+        self.file.flush()
     }
 }
 
-impl<'a> Read for FileLock<'a> {
+impl<'a, M: Mode> Read for FileLock<'a, M> {
     #[inline(always)]
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+        self.file.read(buf)
     }
 }
 
-impl<'a> Seek for FileLock<'a> {
+impl<'a, M: Mode> Seek for FileLock<'a, M> {
     #[inline(always)]
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.0.seek(pos)
+        self.file.seek(pos)
     }
 }
 
-impl<'a> Deref for FileLock<'a> {
+impl<'a> Deref for FileLock<'a, Exclusive> {
     type Target = &'a File;
 
     #[inline(always)]
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.file
     }
 }
 
-impl<'a> DerefMut for FileLock<'a> {
+impl<'a> DerefMut for FileLock<'a, Exclusive> {
     #[inline(always)]
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.file
     }
 }
 
-impl<'a> Drop for FileLock<'a> {
+impl<'a, M: Mode> Drop for FileLock<'a, M> {
     fn drop(&mut self) {
-        if let Err(e) = self.0.unlock() {
-            if panicking() {
-                eprintln!("error unlocking file lock: {}", e)
-            } else {
-                panic!("error unlocking file lock: {}", e)
+        if let Err(e) = self.state.unlock(self.file) {
+            self.state.report(e, self.file)
+        }
+    }
+}
+
+/// Guard whose lock was converted between shared and exclusive modes.
+#[derive(Debug)]
+pub struct Converted<G> {
+    /// The guard, now holding the lock in the requested mode.
+    pub guard: G,
+    /// Whether the lock was converted without being released in between, which is never the
+    /// case with `flock` nor `LockFileEx` but always is with `fcntl` locks.
+    pub atomic: bool,
+}
+
+/// Permissions [`open_lock_file`] is called with by the path-based constructors of [`FileLock`]
+/// unless [`LockOptions::file_mode`] says otherwise.
+pub const LOCK_FILE_MODE: u32 = 0o644;
+
+/// Opens `path` for reading and writing, creating it with the `mode` permission bits if it
+/// doesn't exist, without truncating it nor creating any missing parent directory.
+///
+/// `mode` is subject to the process umask and only has effect on Unix.
+pub fn open_lock_file<P: AsRef<Path>>(path: P, mode: u32) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    options.open(path)
+}
+
+fn open_lock_file_shared(path: &Path, mode: u32) -> io::Result<File> {
+    match open_lock_file(path, mode) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied && path.exists() => File::open(path),
+        r => r,
+    }
+}
+
+/// Everything guards keep about the lock they hold besides the file.
+#[derive(Debug)]
+struct LockState {
+    backend: &'static dyn LockBackend,
+    mode: LockMode,
+    acquisition: Acquisition,
+    acquired_at: Instant,
+    policy: Option<DropPolicy>,
+    #[cfg(unix)]
+    registration: Option<Registration>,
+    /// Boxed as it's only there while the deadlock detection is set.
+    #[cfg(unix)]
+    held: Option<Box<Held>>,
+    /// Whether the lock was lost by a failed conversion.
+    lost: bool,
+    /// Whether blocking conversions interrupted by a signal wait again.
+    restart: bool,
+}
+
+impl LockState {
+    fn new(backend: &'static dyn LockBackend, mode: LockMode, acquisition: Acquisition) -> Self {
+        Self {
+            backend,
+            mode,
+            acquisition,
+            acquired_at: Instant::now(),
+            policy: None,
+            #[cfg(unix)]
+            registration: None,
+            #[cfg(unix)]
+            held: None,
+            lost: false,
+            restart: true,
+        }
+    }
+
+    /// Converts the lock held on `f` to `mode` with [`acquire::convert`] recording it, and the
+    /// wait for the deadlock detection if blocking.
+    fn convert(&mut self, f: &File, mode: LockMode, wait: bool) -> io::Result<bool> {
+        if self.mode == mode {
+            return Ok(true);
+        }
+
+        // Blocking conversions wait like acquisitions do, keeping the lock only if atomic.
+        #[cfg(unix)]
+        let waiting = match (&self.held, deadlock::deadlock_detection()) {
+            (Some(held), Some(detection)) if wait => {
+                let kept = self.backend.converts_atomically();
+                Some(held.wait_converted(detection, mode, kept)?)
+            }
+            _ => None,
+        };
+
+        #[cfg(unix)]
+        let r = match &self.registration {
+            Some(registration) => {
+                registration.convert(self.backend, self.mode, mode, wait, self.restart)
             }
+            None => acquire::convert(self.backend, f, self.mode, mode, wait, self.restart),
+        };
+        #[cfg(not(unix))]
+        let r = acquire::convert(self.backend, f, self.mode, mode, wait, self.restart);
+        #[cfg(unix)]
+        drop(waiting);
+
+        match &r {
+            Ok(_) if wait => self.converted(mode, Acquisition::Blocking),
+            Ok(_) => self.converted(mode, Acquisition::Try),
+            Err(e) if e.lost => {
+                self.lost = true;
+
+                #[cfg(unix)]
+                if let Some(held) = self.held.take() {
+                    held.release();
+                }
+            }
+            // The lock was released and taken again in the same mode.
+            Err(e)
+                if !wait
+                    && !self.backend.converts_atomically()
+                    && acquire::is_contended(&e.error) =>
+            {
+                self.converted(self.mode, Acquisition::Try)
+            }
+            Err(_) => (),
         }
+
+        r.map_err(|e| e.error)
     }
-}
\ No newline at end of file
+
+    /// Records the lock as taken by polling, as done by a single non-blocking attempt of a series.
+    fn polled(mut self) -> Self {
+        self.acquisition = Acquisition::Polling;
+        self
+    }
+
+    fn converted(&mut self, mode: LockMode, acquisition: Acquisition) {
+        self.mode = mode;
+        self.acquisition = acquisition;
+        self.acquired_at = Instant::now();
+
+        #[cfg(unix)]
+        if let Some(held) = &mut self.held {
+            held.converted(mode);
+        }
+    }
+
+    /// Unlocks `f` with the backend it was locked with, or gives up the share of the guard in the
+    /// lock of the process if it's in the registry.
+    fn unlock(&self, f: &File) -> io::Result<()> {
+        #[cfg(unix)]
+        if let Some(held) = &self.held {
+            held.release();
+        }
+
+        #[cfg(unix)]
+        if let Some(registration) = &self.registration {
+            return registration.release(self.backend);
+        }
+
+        if self.lost {
+            return Ok(());
+        }
+
+        self.backend.unlock(f)
+    }
+
+    /// Handles `e`, the error unlocking `f`, following the policy of the guard.
+    fn report(&self, e: io::Error, f: &File) {
+        policy::report(self.policy.as_ref(), e, f)
+    }
+}
@@ -0,0 +1,223 @@
+use ::std::{
+    fs::File,
+    io, mem,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    acquire::Wait, file_id::FileId, DropPolicy, LockMode, LockOptions, LockState, RetryPolicy,
+};
+
+/// Guard holding locks on several files at once, unlocking them all at [dropping][`Drop`].
+///
+/// The files are always locked in the order of their device and inode numbers whichever order
+/// they were given in, so processes locking overlapping sets of files can't deadlock each other.
+/// A file given more than once is locked a single time, exclusive if any of the modes it was given
+/// with is.
+#[derive(Debug)]
+pub struct LockSet {
+    locks: Vec<Locked>,
+}
+
+/// Files to lock into a [`LockSet`] along with their modes.
+#[derive(Debug, Default)]
+pub struct LockSetBuilder {
+    options: LockOptions,
+    entries: Vec<(Source, LockMode)>,
+}
+
+#[derive(Debug)]
+enum Source {
+    File(File),
+    Path(PathBuf),
+}
+
+/// File to lock opened from its source.
+struct Opened {
+    id: FileId,
+    file: File,
+    path: Option<PathBuf>,
+    mode: LockMode,
+}
+
+#[derive(Debug)]
+struct Locked {
+    file: File,
+    path: Option<PathBuf>,
+    state: LockState,
+}
+
+impl LockSetBuilder {
+    /// Creates an empty set locking with [`LockOptions::new`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the options the files are locked with.
+    pub fn options(mut self, options: LockOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Adds `f` to be locked in `mode`.
+    pub fn file(mut self, f: File, mode: LockMode) -> Self {
+        self.entries.push((Source::File(f), mode));
+        self
+    }
+
+    /// Adds the file at `path` to be locked in `mode`, opened like [`LockOptions::open`] does when
+    /// locking the set.
+    pub fn path<P: Into<PathBuf>>(mut self, path: P, mode: LockMode) -> Self {
+        self.entries.push((Source::Path(path.into()), mode));
+        self
+    }
+
+    fn lock_with(self, wait: Wait) -> io::Result<LockSet> {
+        let files = open(&self.options, self.entries)?;
+        let mut set = LockSet {
+            locks: Vec::with_capacity(files.len()),
+        };
+
+        // Dropping `set` on failure unlocks the files locked so far.
+        for f in files {
+            let state = self.options.lock(&f.file, f.mode, wait.clone())?;
+            set.locks.push(Locked {
+                file: f.file,
+                path: f.path,
+                state,
+            });
+        }
+
+        Ok(set)
+    }
+
+    /// Locks all the files if it can be done without waiting for any of them, unlocking the ones
+    /// already locked and returning the error otherwise.
+    pub fn try_lock(self) -> io::Result<LockSet> {
+        self.lock_with(Wait::No)
+    }
+
+    /// Locks all the files, waiting for each as long as needed, and returns any error that could
+    /// have caused after unlocking the ones already locked.
+    pub fn lock(self) -> io::Result<LockSet> {
+        self.lock_with(Wait::Blocking)
+    }
+
+    /// Locks all the files polling until `timeout` elapses for all of them, returning an error of
+    /// kind [`io::ErrorKind::TimedOut`] if it does or any other error that could have caused after
+    /// unlocking the ones already locked.
+    pub fn lock_timeout(self, timeout: Duration) -> io::Result<LockSet> {
+        self.lock_with(Wait::timeout(timeout))
+    }
+
+    /// Locks all the files polling until `deadline` is reached for all of them, returning an error
+    /// of kind [`io::ErrorKind::TimedOut`] if it is or any other error that could have caused after
+    /// unlocking the ones already locked.
+    pub fn lock_deadline(self, deadline: Instant) -> io::Result<LockSet> {
+        self.lock_with(Wait::deadline(deadline))
+    }
+
+    /// Locks all the files polling for each following `policy` and returning any error that could
+    /// have caused after unlocking the ones already locked, see [`RetryPolicy`] for the ones it
+    /// gives up with.
+    pub fn lock_with_retry(self, policy: &RetryPolicy) -> io::Result<LockSet> {
+        self.lock_with(Wait::Retry(policy.clone(), None))
+    }
+}
+
+impl LockSet {
+    /// Creates an empty [`LockSetBuilder`].
+    #[inline(always)]
+    pub fn builder() -> LockSetBuilder {
+        LockSetBuilder::new()
+    }
+
+    /// Returns the number of files locked.
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.locks.len()
+    }
+
+    /// Returns whether no file is locked.
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.locks.is_empty()
+    }
+
+    /// Returns the locked files in locking order along with the path they were given by, if any,
+    /// and the mode they're locked in.
+    pub fn iter(&self) -> impl Iterator<Item = (&File, Option<&Path>, LockMode)> {
+        self.locks
+            .iter()
+            .map(|l| (&l.file, l.path.as_deref(), l.state.mode))
+    }
+
+    /// Sets the policy followed if unlocking any of the files fails at [dropping][`Drop`],
+    /// overriding the global one.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        for l in &mut self.locks {
+            l.state.policy = Some(policy.clone());
+        }
+    }
+
+    /// Unlocks all the files in reverse locking order, returning the first error that could have
+    /// caused instead of reporting them like [dropping][`Drop`] does.
+    pub fn unlock(mut self) -> io::Result<()> {
+        let mut r = Ok(());
+
+        while let Some(l) = self.locks.pop() {
+            if let Err(e) = l.state.unlock(&l.file) {
+                r = r.and(Err(e));
+            }
+        }
+
+        r
+    }
+}
+
+impl Drop for LockSet {
+    fn drop(&mut self) {
+        while let Some(l) = self.locks.pop() {
+            if let Err(e) = l.state.unlock(&l.file) {
+                l.state.report(e, &l.file);
+            }
+        }
+    }
+}
+
+/// Opens the files of `entries` as `options` says and sorts them in locking order, merging the
+/// ones given more than once.
+fn open(options: &LockOptions, entries: Vec<(Source, LockMode)>) -> io::Result<Vec<Opened>> {
+    let mut files = Vec::with_capacity(entries.len());
+
+    for (source, mode) in entries {
+        let (file, path) = match source {
+            Source::File(file) => (file, None),
+            Source::Path(path) => (options.open_file(&path, mode)?, Some(path)),
+        };
+
+        files.push(Opened {
+            id: FileId::of(&file)?,
+            file,
+            path,
+            mode,
+        });
+    }
+
+    files.sort_by_key(|f| f.id);
+    files.dedup_by(|b, a| {
+        if a.id != b.id {
+            return false;
+        }
+
+        // Keeping the handle the file can be locked exclusive through.
+        if b.mode == LockMode::Exclusive {
+            mem::swap(a, b);
+        }
+
+        true
+    });
+
+    Ok(files)
+}
@@ -0,0 +1,34 @@
+use ::std::fmt::Debug;
+
+use crate::LockMode;
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for super::Shared {}
+    impl Sealed for super::Exclusive {}
+}
+
+/// Mode a guard holds its lock in, encoded in its type so only exclusive guards allow writing.
+///
+/// This trait is sealed, [`Shared`] and [`Exclusive`] being its only implementors.
+pub trait Mode: sealed::Sealed + Debug + Send + Sync + Unpin + 'static {
+    /// The mode as a value.
+    const MODE: LockMode;
+}
+
+/// Type-level [`LockMode::Shared`], for guards that only allow reading and seeking.
+#[derive(Debug)]
+pub enum Shared {}
+
+/// Type-level [`LockMode::Exclusive`], for guards that allow writing and mutating the file too.
+#[derive(Debug)]
+pub enum Exclusive {}
+
+impl Mode for Shared {
+    const MODE: LockMode = LockMode::Shared;
+}
+
+impl Mode for Exclusive {
+    const MODE: LockMode = LockMode::Exclusive;
+}
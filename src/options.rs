@@ -0,0 +1,349 @@
+use ::std::{
+    borrow::Borrow,
+    fs::File,
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    acquire::{self, Retry, Wait},
+    cancel::{self, Cancellation},
+    open_lock_file, open_lock_file_shared, Flock, LockBackend, LockInfo, LockMode, LockState, Mode,
+    OwnedFileLock, RetryPolicy, LOCK_FILE_MODE,
+};
+
+#[cfg(unix)]
+use crate::{
+    deadlock::{self, deadlock_detection, Held},
+    file_id::FileId,
+    registry::{self, registry_policy},
+    RegistryPolicy,
+};
+
+#[cfg(all(doc, unix))]
+use crate::set_deadlock_detection;
+
+#[cfg(feature = "tokio")]
+use crate::AsyncFileLock;
+
+#[cfg(doc)]
+use crate::Cancelled;
+
+/// Settings to lock files with beyond the ones the constructors of the guards take, like the
+/// [backend][`LockBackend`] to use.
+///
+/// The mode of the guards is given by their type, for example:
+///
+/// ```no_run
+/// # use raii_flock::{Exclusive, LockOptions, OwnedFileLock};
+/// # #[cfg(target_os = "linux")]
+/// # fn main() -> std::io::Result<()> {
+/// let lock: OwnedFileLock<_, Exclusive> = LockOptions::new()
+///     .backend(&raii_flock::OfdFcntl)
+///     .open("app.lock")?;
+/// # Ok(())
+/// # }
+/// # #[cfg(not(target_os = "linux"))]
+/// # fn main() {}
+/// ```
+#[derive(Clone, Debug)]
+pub struct LockOptions {
+    backend: &'static dyn LockBackend,
+    #[cfg(unix)]
+    registry: Option<RegistryPolicy>,
+    info: Option<LockInfo>,
+    file_mode: u32,
+    restart: bool,
+    #[cfg(target_os = "linux")]
+    timeout_signal: Option<i32>,
+}
+
+impl LockOptions {
+    /// Creates options locking with [`Flock`] and following the global [`registry_policy`], the
+    /// settings the constructors of the guards use.
+    pub fn new() -> Self {
+        Self {
+            backend: &Flock,
+            #[cfg(unix)]
+            registry: registry_policy(),
+            info: None,
+            file_mode: LOCK_FILE_MODE,
+            restart: true,
+            #[cfg(target_os = "linux")]
+            timeout_signal: None,
+        }
+    }
+
+    /// Sets the backend used to lock and unlock the files.
+    pub fn backend(mut self, backend: &'static dyn LockBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Sets the permissions the path-based constructors create missing files with, subject to
+    /// the process umask and only having effect on Unix. [`LOCK_FILE_MODE`] is the default.
+    pub fn file_mode(mut self, mode: u32) -> Self {
+        self.file_mode = mode;
+        self
+    }
+
+    /// Sets the policy followed if the process already holds a lock on the files, `None` meaning
+    /// the registry isn't used.
+    #[cfg(unix)]
+    pub fn registry(mut self, policy: Option<RegistryPolicy>) -> Self {
+        self.registry = policy;
+        self
+    }
+
+    /// Sets the record exclusive guards write into the files after locking them, recording the
+    /// time each was locked at, `None` meaning nothing is written. This is the default.
+    pub fn info(mut self, info: Option<LockInfo>) -> Self {
+        self.info = info;
+        self
+    }
+
+    /// Sets whether blocking acquisitions interrupted by a signal wait again, the default, or fail
+    /// with an error of kind [`io::ErrorKind::Interrupted`], as well as blocking conversions of
+    /// the guards between modes.
+    pub fn restart_interrupted(mut self, restart: bool) -> Self {
+        self.restart = restart;
+        self
+    }
+
+    /// Sets a signal interrupting a single blocking call timeout and deadline bounded
+    /// acquisitions wait with instead of polling, `None` meaning they poll. This is the default.
+    ///
+    /// The signal is sent to the waiting thread by a timer once the time is up, and every few
+    /// milliseconds after until the call returns, failing with an error of kind
+    /// [`io::ErrorKind::TimedOut`]. Its handler is replaced by one doing nothing the first time
+    /// it's used, so it should be one the program doesn't handle otherwise, like `SIGALRM` often
+    /// is. Interruptions by other signals follow [`LockOptions::restart_interrupted`].
+    #[cfg(target_os = "linux")]
+    pub fn timeout_signal(mut self, signal: Option<i32>) -> Self {
+        self.timeout_signal = signal;
+        self
+    }
+
+    /// Locks `f` in `mode` waiting as `wait` says, writes the record into it if exclusive and
+    /// returns the state of the guard holding it.
+    pub(crate) fn lock(&self, f: &File, mode: LockMode, wait: Wait) -> io::Result<LockState> {
+        #[cfg(target_os = "linux")]
+        let wait = match (wait, self.timeout_signal) {
+            (Wait::Retry(_, Some(deadline)), Some(signal)) => {
+                Wait::Alarm(deadline, signal, self.restart)
+            }
+            (wait, _) => wait,
+        };
+        let wait = match wait {
+            Wait::Blocking if !self.restart => Wait::Interruptible,
+            wait => wait,
+        };
+        let mut state = self.acquire(f, mode, wait)?;
+        state.restart = self.restart;
+
+        if let (Some(info), LockMode::Exclusive) = (&self.info, mode) {
+            if let Err(e) = info.now().write_to(f) {
+                let _ = state.unlock(f);
+                return Err(e);
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Locks `f` like [`LockOptions::acquire_untracked`] does, recording the guard and the wait
+    /// for the [deadlock detection][`set_deadlock_detection`] if it's set and the locks of the
+    /// backend can conflict within the process.
+    fn acquire(&self, f: &File, mode: LockMode, wait: Wait) -> io::Result<LockState> {
+        #[cfg(unix)]
+        if let Some(detection) = deadlock_detection().filter(|_| !self.backend.owned_by_process()) {
+            let file = FileId::of(f)?;
+            // The registry never waits for the guards of the process with the `Error` policy, or
+            // with the `Share` one when it shares their exclusive lock.
+            let waits = !matches!(wait, Wait::No)
+                && match self.registry {
+                    Some(RegistryPolicy::Error) => false,
+                    Some(RegistryPolicy::Share) => !registry::held_exclusive(file),
+                    _ => true,
+                };
+
+            let waiting = if waits {
+                Some(deadlock::wait(detection, file, mode)?)
+            } else {
+                None
+            };
+            let mut state = self.acquire_untracked(f, mode, wait)?;
+            drop(waiting);
+
+            state.held = Some(Box::new(Held::new(file, mode)));
+            return Ok(state);
+        }
+
+        self.acquire_untracked(f, mode, wait)
+    }
+
+    fn acquire_untracked(&self, f: &File, mode: LockMode, wait: Wait) -> io::Result<LockState> {
+        #[cfg(unix)]
+        if let Some(policy) = self.registry {
+            let registration = registry::lock(policy, self.backend, f, mode, &wait)?;
+            let mut state = LockState::new(self.backend, mode, wait.acquisition());
+            state.registration = Some(registration);
+            return Ok(state);
+        }
+
+        acquire::lock(self.backend, f, mode, &wait)?;
+        Ok(LockState::new(self.backend, mode, wait.acquisition()))
+    }
+
+    /// Creates a guard locking `f` if it can be done without waiting and returning any error that
+    /// could have caused.
+    pub fn try_wrap<F: Borrow<File>, M: Mode>(&self, f: F) -> io::Result<OwnedFileLock<F, M>> {
+        let state = self.lock(f.borrow(), M::MODE, Wait::No)?;
+        Ok(OwnedFileLock::new(f, state))
+    }
+
+    /// Creates a guard locking `f`, waiting as long as needed, and returning any error that could
+    /// have caused.
+    pub fn wrap<F: Borrow<File>, M: Mode>(&self, f: F) -> io::Result<OwnedFileLock<F, M>> {
+        let state = self.lock(f.borrow(), M::MODE, Wait::Blocking)?;
+        Ok(OwnedFileLock::new(f, state))
+    }
+
+    /// Creates a guard polling until `f` is locked or `timeout` elapses, returning an error of
+    /// kind [`io::ErrorKind::TimedOut`] in the latter case or any other error that could have
+    /// caused.
+    pub fn wrap_timeout<F: Borrow<File>, M: Mode>(
+        &self,
+        f: F,
+        timeout: Duration,
+    ) -> io::Result<OwnedFileLock<F, M>> {
+        let state = self.lock(f.borrow(), M::MODE, Wait::timeout(timeout))?;
+        Ok(OwnedFileLock::new(f, state))
+    }
+
+    /// Creates a guard polling until `f` is locked or `deadline` is reached, returning an error of
+    /// kind [`io::ErrorKind::TimedOut`] in the latter case or any other error that could have
+    /// caused.
+    pub fn wrap_deadline<F: Borrow<File>, M: Mode>(
+        &self,
+        f: F,
+        deadline: Instant,
+    ) -> io::Result<OwnedFileLock<F, M>> {
+        let state = self.lock(f.borrow(), M::MODE, Wait::deadline(deadline))?;
+        Ok(OwnedFileLock::new(f, state))
+    }
+
+    /// Creates a guard polling until `f` is locked following `policy` and returning any error
+    /// that could have caused, see [`RetryPolicy`] for the ones it gives up with.
+    pub fn wrap_with_retry<F: Borrow<File>, M: Mode>(
+        &self,
+        f: F,
+        policy: &RetryPolicy,
+    ) -> io::Result<OwnedFileLock<F, M>> {
+        let state = self.lock(f.borrow(), M::MODE, Wait::Retry(policy.clone(), None))?;
+        Ok(OwnedFileLock::new(f, state))
+    }
+
+    /// Creates a guard polling until `f` is locked following the default [`RetryPolicy`], giving
+    /// up with an error with a [`Cancelled`] payload as soon as `cancel` is signalled, and
+    /// returning any other error that could have caused.
+    pub fn wrap_cancellable<F: Borrow<File>, M: Mode, C: Cancellation + ?Sized>(
+        &self,
+        f: F,
+        cancel: &C,
+    ) -> io::Result<OwnedFileLock<F, M>> {
+        let state = self.lock_cancellable(f.borrow(), M::MODE, cancel)?;
+        Ok(OwnedFileLock::new(f, state))
+    }
+
+    /// Locks `f` in `mode` with non-blocking attempts, waiting on `cancel` in between.
+    pub(crate) fn lock_cancellable<C: Cancellation + ?Sized>(
+        &self,
+        f: &File,
+        mode: LockMode,
+        cancel: &C,
+    ) -> io::Result<LockState> {
+        let policy = RetryPolicy::default();
+        let mut retry = Retry::new(&policy, None);
+
+        if cancel.is_cancelled() {
+            return Err(cancel::cancelled());
+        }
+
+        loop {
+            match self.lock(f, mode, Wait::No) {
+                Err(e) if acquire::is_contended(&e) => {
+                    if cancel.wait_cancelled(retry.failed(e)?) {
+                        return Err(cancel::cancelled());
+                    }
+                }
+                r => return r.map(LockState::polled),
+            }
+        }
+    }
+
+    /// Opens `path` with [`open_lock_file`] using the [file mode][`LockOptions::file_mode`] and
+    /// wraps it with [`LockOptions::try_wrap`].
+    ///
+    /// If the guard is shared and the file exists but can't be opened for writing it's opened
+    /// read-only instead.
+    pub fn try_open<P: AsRef<Path>, M: Mode>(&self, path: P) -> io::Result<OwnedFileLock<File, M>> {
+        self.try_wrap(self.open_file(path.as_ref(), M::MODE)?)
+    }
+
+    /// Opens `path` with [`open_lock_file`] using the [file mode][`LockOptions::file_mode`] and
+    /// wraps it with [`LockOptions::wrap`].
+    ///
+    /// If the guard is shared and the file exists but can't be opened for writing it's opened
+    /// read-only instead.
+    pub fn open<P: AsRef<Path>, M: Mode>(&self, path: P) -> io::Result<OwnedFileLock<File, M>> {
+        self.wrap(self.open_file(path.as_ref(), M::MODE)?)
+    }
+
+    /// Returns whether blocking acquisitions interrupted by a signal wait again.
+    #[cfg(target_os = "linux")]
+    #[inline(always)]
+    pub(crate) fn restarts_interrupted(&self) -> bool {
+        self.restart
+    }
+
+    /// Returns the permissions lock files are created with.
+    #[cfg(unix)]
+    #[inline(always)]
+    pub(crate) fn lock_file_mode(&self) -> u32 {
+        self.file_mode
+    }
+
+    /// Opens `path` for a guard locking it in `mode`, creating it with the file mode if missing.
+    pub(crate) fn open_file(&self, path: &Path, mode: LockMode) -> io::Result<File> {
+        match mode {
+            LockMode::Shared => open_lock_file_shared(path, self.file_mode),
+            LockMode::Exclusive => open_lock_file(path, self.file_mode),
+        }
+    }
+
+    /// Creates an asynchronous guard locking `f` if it can be done without waiting and returning
+    /// any error that could have caused.
+    #[cfg(feature = "tokio")]
+    pub fn try_wrap_async<M: Mode>(&self, f: tokio::fs::File) -> io::Result<AsyncFileLock<M>> {
+        AsyncFileLock::try_wrap_with(f, self)
+    }
+
+    /// Creates an asynchronous guard waiting until `f` is locked and returning any error that
+    /// could have caused.
+    ///
+    /// Waiting for the guards of the process holding `f` is done by polling too, so even the
+    /// [`RegistryPolicy::Wait`] policy doesn't block.
+    #[cfg(feature = "tokio")]
+    pub async fn wrap_async<M: Mode>(&self, f: tokio::fs::File) -> io::Result<AsyncFileLock<M>> {
+        AsyncFileLock::wrap_with(f, self, None).await
+    }
+}
+
+impl Default for LockOptions {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,310 @@
+use ::std::{
+    borrow::Borrow,
+    fs::{File, Metadata},
+    io::{self, prelude::*, SeekFrom},
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::Deref,
+    ptr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    Acquisition, Cancellation, Converted, DropPolicy, Exclusive, LockError, LockMode, LockOptions,
+    LockState, Mode, RetryPolicy, Shared,
+};
+
+#[cfg(doc)]
+use {crate::Cancelled, fs2::FileExt};
+
+/// Owned counterpart of [`FileLock`][`crate::FileLock`], holding `F` instead of borrowing a
+/// file and calling [`FileExt::unlock`] at [dropping][`Drop`].
+///
+/// `F` can be anything that [borrows][`Borrow`] a [`File`], like [`File`] itself,
+/// [`Box<File>`][`Box`], [`Arc<File>`][`std::sync::Arc`] or `&File`. `M` is either [`Shared`] or
+/// [`Exclusive`], only the latter implementing [`Write`] and giving access to the file.
+#[derive(Debug)]
+pub struct OwnedFileLock<F: Borrow<File>, M: Mode> {
+    file: F,
+    state: LockState,
+    mode: PhantomData<M>,
+}
+
+impl<F: Borrow<File>, M: Mode> OwnedFileLock<F, M> {
+    pub(crate) fn new(file: F, state: LockState) -> Self {
+        Self {
+            file,
+            state,
+            mode: PhantomData,
+        }
+    }
+
+    fn cast<N: Mode>(self) -> OwnedFileLock<F, N> {
+        let (file, state) = self.into_parts();
+
+        OwnedFileLock {
+            file,
+            state,
+            mode: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    fn file(&self) -> &File {
+        self.file.borrow()
+    }
+
+    /// Returns the mode the lock is held in, which is always `M::MODE`.
+    #[inline(always)]
+    pub fn mode(&self) -> LockMode {
+        self.state.mode
+    }
+
+    /// Returns how the lock was acquired.
+    #[inline(always)]
+    pub fn acquisition(&self) -> Acquisition {
+        self.state.acquisition
+    }
+
+    /// Returns when the lock was acquired, or last converted between modes.
+    #[inline(always)]
+    pub fn acquired_at(&self) -> Instant {
+        self.state.acquired_at
+    }
+
+    /// Returns whether the guard lost its lock, as a failed non-atomic conversion may release it
+    /// without being able to take it again, in which case it should be dropped.
+    #[inline(always)]
+    pub fn lost(&self) -> bool {
+        self.state.lost
+    }
+
+    /// Sets the policy followed if unlocking fails at [dropping][`Drop`], overriding the global
+    /// one.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.state.policy = Some(policy);
+    }
+
+    /// Queries metadata about the underlying file with [`File::metadata`].
+    #[inline(always)]
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        self.file().metadata()
+    }
+
+    fn into_parts(self) -> (F, LockState) {
+        let this = ManuallyDrop::new(self);
+        // SAFETY: `this` is never dropped nor used again after moving its fields out.
+        unsafe { (ptr::read(&this.file), ptr::read(&this.state)) }
+    }
+
+    /// Unlocks the file the same way [dropping][`Drop`] does and gives the wrapped value back.
+    pub fn into_inner(self) -> F {
+        let (f, state) = self.into_parts();
+
+        if let Err(e) = state.unlock(f.borrow()) {
+            state.report(e, f.borrow());
+        }
+
+        f
+    }
+
+    /// Unlocks the file and gives the wrapped value back, returning it along with the error
+    /// instead of reporting it like [dropping][`Drop`] does if unlocking fails.
+    pub fn try_into_inner(self) -> Result<F, LockError<F>> {
+        let (f, state) = self.into_parts();
+
+        match state.unlock(f.borrow()) {
+            Ok(()) => Ok(f),
+            Err(e) => Err(LockError::new(f, e)),
+        }
+    }
+
+    /// Unlocks the file returning any error that could have caused instead of reporting it like
+    /// [dropping][`Drop`] does.
+    pub fn unlock(self) -> io::Result<()> {
+        self.try_into_inner().map(drop).map_err(io::Error::from)
+    }
+}
+
+impl<F: Borrow<File>> OwnedFileLock<F, Shared> {
+    /// Creates a `Self` instance calling [`FileExt::try_lock_shared`] on `f` and returning any
+    /// error that could have caused.
+    pub fn try_wrap_shared(f: F) -> io::Result<Self> {
+        LockOptions::new().try_wrap(f)
+    }
+
+    /// Creates a `Self` instance calling [`FileExt::lock_shared`] on `f`, again if interrupted by a
+    /// signal, and returning any error that could have caused.
+    pub fn wrap_shared(f: F) -> io::Result<Self> {
+        LockOptions::new().wrap(f)
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_shared`] on `f` until it succeeds
+    /// or `timeout` elapses, returning an error of kind [`io::ErrorKind::TimedOut`] in the latter
+    /// case or any other error that could have caused.
+    pub fn wrap_shared_timeout(f: F, timeout: Duration) -> io::Result<Self> {
+        LockOptions::new().wrap_timeout(f, timeout)
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_shared`] on `f` until it succeeds
+    /// or `deadline` is reached, returning an error of kind [`io::ErrorKind::TimedOut`] in the
+    /// latter case or any other error that could have caused.
+    pub fn wrap_shared_deadline(f: F, deadline: Instant) -> io::Result<Self> {
+        LockOptions::new().wrap_deadline(f, deadline)
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_shared`] on `f` following `policy`
+    /// and returning any error that could have caused, see [`RetryPolicy`] for the ones it gives
+    /// up with.
+    pub fn wrap_shared_with_retry(f: F, policy: &RetryPolicy) -> io::Result<Self> {
+        LockOptions::new().wrap_with_retry(f, policy)
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_shared`] on `f` until it succeeds,
+    /// giving up with an error with a [`Cancelled`] payload as soon as `cancel` is signalled, and
+    /// returning any other error that could have caused.
+    pub fn wrap_shared_cancellable<C: Cancellation + ?Sized>(f: F, cancel: &C) -> io::Result<Self> {
+        LockOptions::new().wrap_cancellable(f, cancel)
+    }
+
+    /// Converts the lock to exclusive, waiting for it if needed, and returns any error that could
+    /// have caused.
+    ///
+    /// Other processes may take the lock while converting if the conversion isn't
+    /// [atomic][`Converted::atomic`], so anything read before should be read again.
+    pub fn upgrade(mut self) -> io::Result<Converted<OwnedFileLock<F, Exclusive>>> {
+        let atomic = self
+            .state
+            .convert(self.file.borrow(), LockMode::Exclusive, true)?;
+        Ok(Converted {
+            guard: self.cast(),
+            atomic,
+        })
+    }
+
+    /// Converts the lock to exclusive if it can be done without waiting, giving `self` back with
+    /// the error that caused the conversion to fail otherwise.
+    ///
+    /// If the conversion isn't [atomic][`Converted::atomic`] the lock is released first and taken
+    /// shared again if that can be done without waiting, `self` being given back having
+    /// [lost][`OwnedFileLock::lost`] it otherwise as someone else took it meanwhile.
+    pub fn try_upgrade(
+        mut self,
+    ) -> Result<Converted<OwnedFileLock<F, Exclusive>>, LockError<Self>> {
+        match self
+            .state
+            .convert(self.file.borrow(), LockMode::Exclusive, false)
+        {
+            Ok(atomic) => Ok(Converted {
+                guard: self.cast(),
+                atomic,
+            }),
+            Err(e) => Err(LockError::new(self, e)),
+        }
+    }
+}
+
+impl<F: Borrow<File>> OwnedFileLock<F, Exclusive> {
+    /// Creates a `Self` instance calling [`FileExt::try_lock_exclusive`] on `f` and returning
+    /// any error that could have caused.
+    pub fn try_wrap_exclusive(f: F) -> io::Result<Self> {
+        LockOptions::new().try_wrap(f)
+    }
+
+    /// Creates a `Self` instance calling [`FileExt::lock_exclusive`] on `f`, again if interrupted
+    /// by a signal, and returning any error that could have caused.
+    pub fn wrap_exclusive(f: F) -> io::Result<Self> {
+        LockOptions::new().wrap(f)
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_exclusive`] on `f` until it succeeds
+    /// or `timeout` elapses, returning an error of kind [`io::ErrorKind::TimedOut`] in the latter
+    /// case or any other error that could have caused.
+    pub fn wrap_exclusive_timeout(f: F, timeout: Duration) -> io::Result<Self> {
+        LockOptions::new().wrap_timeout(f, timeout)
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_exclusive`] on `f` until it succeeds
+    /// or `deadline` is reached, returning an error of kind [`io::ErrorKind::TimedOut`] in the
+    /// latter case or any other error that could have caused.
+    pub fn wrap_exclusive_deadline(f: F, deadline: Instant) -> io::Result<Self> {
+        LockOptions::new().wrap_deadline(f, deadline)
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_exclusive`] on `f` following `policy`
+    /// and returning any error that could have caused, see [`RetryPolicy`] for the ones it gives
+    /// up with.
+    pub fn wrap_exclusive_with_retry(f: F, policy: &RetryPolicy) -> io::Result<Self> {
+        LockOptions::new().wrap_with_retry(f, policy)
+    }
+
+    /// Creates a `Self` instance polling [`FileExt::try_lock_exclusive`] on `f` until it succeeds,
+    /// giving up with an error with a [`Cancelled`] payload as soon as `cancel` is signalled, and
+    /// returning any other error that could have caused.
+    pub fn wrap_exclusive_cancellable<C: Cancellation + ?Sized>(
+        f: F,
+        cancel: &C,
+    ) -> io::Result<Self> {
+        LockOptions::new().wrap_cancellable(f, cancel)
+    }
+
+    /// Converts the lock to shared and returns any error that could have caused.
+    pub fn downgrade(mut self) -> io::Result<Converted<OwnedFileLock<F, Shared>>> {
+        let atomic = self
+            .state
+            .convert(self.file.borrow(), LockMode::Shared, true)?;
+        Ok(Converted {
+            guard: self.cast(),
+            atomic,
+        })
+    }
+
+    /// Returns a reference to the wrapped value.
+    #[inline(always)]
+    pub fn get_ref(&self) -> &F {
+        &self.file
+    }
+}
+
+impl<F: Borrow<File>> Write for OwnedFileLock<F, Exclusive> {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file().write(buf)
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> io::Result<()> {
+        self.file().flush()
+    }
+}
+
+impl<F: Borrow<File>, M: Mode> Read for OwnedFileLock<F, M> {
+    #[inline(always)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file().read(buf)
+    }
+}
+
+impl<F: Borrow<File>, M: Mode> Seek for OwnedFileLock<F, M> {
+    #[inline(always)]
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file().seek(pos)
+    }
+}
+
+impl<F: Borrow<File>> Deref for OwnedFileLock<F, Exclusive> {
+    type Target = File;
+
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        self.file()
+    }
+}
+
+impl<F: Borrow<File>, M: Mode> Drop for OwnedFileLock<F, M> {
+    fn drop(&mut self) {
+        if let Err(e) = self.state.unlock(self.file()) {
+            self.state.report(e, self.file());
+        }
+    }
+}
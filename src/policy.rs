@@ -0,0 +1,91 @@
+use ::std::{
+    fmt,
+    fs::File,
+    io,
+    sync::{Arc, RwLock},
+    thread::panicking,
+};
+
+/// Function called with the error unlocking a file and the file itself.
+pub type DropCallback = dyn Fn(&io::Error, &File) + Send + Sync;
+
+/// What guards do when unlocking fails at [dropping][`Drop`].
+///
+/// The policy of a guard is the global one set with [`set_drop_policy`] unless overridden with
+/// its `set_drop_policy` method.
+#[derive(Clone, Default)]
+pub enum DropPolicy {
+    /// Panics with the error, or prints it to the standard error if the thread is already
+    /// panicking. This is the default.
+    #[default]
+    Panic,
+    /// Prints the error to the standard error.
+    Print,
+    /// Logs the error with [`log::error`].
+    #[cfg(feature = "log")]
+    Log,
+    /// Emits the error as an event with [`tracing::error`].
+    #[cfg(feature = "tracing")]
+    Tracing,
+    /// Does nothing.
+    Ignore,
+    /// Calls the function with the error and the file, from which its identity can be gotten.
+    Callback(Arc<DropCallback>),
+}
+
+static GLOBAL: RwLock<DropPolicy> = RwLock::new(DropPolicy::Panic);
+
+impl DropPolicy {
+    /// Creates a [`DropPolicy::Callback`] calling `f`.
+    pub fn callback<C: Fn(&io::Error, &File) + Send + Sync + 'static>(f: C) -> Self {
+        Self::Callback(Arc::new(f))
+    }
+
+    /// Handles `e`, the error unlocking `f`, following `self`.
+    pub(crate) fn report(&self, e: io::Error, f: &File) {
+        match self {
+            Self::Panic if !panicking() => panic!("error unlocking file lock: {}", e),
+            Self::Panic | Self::Print => eprintln!("error unlocking file lock: {}", e),
+            #[cfg(feature = "log")]
+            Self::Log => log::error!("error unlocking file lock: {}", e),
+            #[cfg(feature = "tracing")]
+            Self::Tracing => tracing::error!(error = %e, "error unlocking file lock"),
+            Self::Ignore => (),
+            Self::Callback(c) => c(&e, f),
+        }
+    }
+}
+
+impl fmt::Debug for DropPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Panic => "Panic",
+            Self::Print => "Print",
+            #[cfg(feature = "log")]
+            Self::Log => "Log",
+            #[cfg(feature = "tracing")]
+            Self::Tracing => "Tracing",
+            Self::Ignore => "Ignore",
+            Self::Callback(_) => "Callback(..)",
+        })
+    }
+}
+
+/// Sets the policy followed by guards that don't override it.
+pub fn set_drop_policy(policy: DropPolicy) {
+    *GLOBAL.write().unwrap_or_else(|e| e.into_inner()) = policy;
+}
+
+/// Returns the policy followed by guards that don't override it.
+pub fn drop_policy() -> DropPolicy {
+    GLOBAL.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Handles `e`, the error unlocking `f`, following `policy` or the global one if it's `None`.
+pub(crate) fn report(policy: Option<&DropPolicy>, e: io::Error, f: &File) {
+    match policy {
+        Some(policy) => policy.report(e, f),
+        // Cloned so the lock isn't held while calling a callback that could set it.
+        None => drop_policy().report(e, f),
+    }
+}
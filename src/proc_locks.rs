@@ -0,0 +1,133 @@
+use ::std::{convert::TryFrom, fs, io};
+
+use crate::{file_id::FileId, LockKind, LockMode};
+
+/// Lock listed in `/proc/locks`, either held or waited for.
+#[derive(Clone, Debug)]
+pub(crate) struct Entry {
+    /// Whether the entry is waiting for the lock listed before instead of holding it.
+    pub(crate) waiting: bool,
+    pub(crate) kind: LockKind,
+    pub(crate) mode: LockMode,
+    /// Process owning the lock, unknown for open file description locks.
+    pub(crate) pid: Option<u32>,
+    pub(crate) file: FileId,
+    /// First byte of the locked range.
+    pub(crate) start: u64,
+    /// Last byte of the locked range, `None` meaning up to the end of the file however it grows.
+    pub(crate) end: Option<u64>,
+}
+
+/// Reads the locks in `/proc/locks`, skipping leases and lines that couldn't be parsed.
+pub(crate) fn read() -> io::Result<Vec<Entry>> {
+    Ok(fs::read_to_string("/proc/locks")?
+        .lines()
+        .filter_map(parse)
+        .collect())
+}
+
+/// Parses a line like `1: -> FLOCK  ADVISORY  WRITE 1234 08:01:5678 0 EOF`, whose last fields
+/// are the first and last bytes of the locked range.
+fn parse(line: &str) -> Option<Entry> {
+    let mut fields = line.split_whitespace();
+    fields.next()?.strip_suffix(':')?.parse::<u64>().ok()?;
+    let mut kind = fields.next()?;
+    let waiting = kind == "->";
+
+    if waiting {
+        kind = fields.next()?;
+    }
+
+    let kind = match kind {
+        "FLOCK" => LockKind::Flock,
+        "POSIX" => LockKind::Posix,
+        "OFDLCK" => LockKind::Ofd,
+        _ => return None,
+    };
+    let mode = match fields.nth(1)? {
+        "READ" => LockMode::Shared,
+        "WRITE" => LockMode::Exclusive,
+        _ => return None,
+    };
+    let pid = fields.next()?.parse::<i64>().ok()?;
+    let mut file = fields.next()?.split(':');
+    let major = u32::from_str_radix(file.next()?, 16).ok()?;
+    let minor = u32::from_str_radix(file.next()?, 16).ok()?;
+    let file = FileId::new(libc::makedev(major, minor), file.next()?.parse().ok()?);
+    let start = fields.next()?.parse().ok()?;
+    let end = match fields.next()? {
+        "EOF" => None,
+        end => Some(end.parse().ok()?),
+    };
+
+    Some(Entry {
+        waiting,
+        kind,
+        mode,
+        pid: u32::try_from(pid).ok(),
+        file,
+        start,
+        end,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_held_lock() {
+        let entry = parse("1: FLOCK  ADVISORY  WRITE 1234 08:01:5678 0 EOF").unwrap();
+
+        assert!(!entry.waiting);
+        assert_eq!(
+            (entry.kind, entry.mode),
+            (LockKind::Flock, LockMode::Exclusive)
+        );
+        assert_eq!(entry.pid, Some(1234));
+        assert_eq!(entry.file, FileId::new(libc::makedev(8, 1), 5678));
+        assert_eq!((entry.start, entry.end), (0, None));
+    }
+
+    #[test]
+    fn parses_waiting_lock() {
+        let entry = parse("2: -> POSIX  ADVISORY  READ 42 fd:1a:99 100 199").unwrap();
+
+        assert!(entry.waiting);
+        assert_eq!(
+            (entry.kind, entry.mode),
+            (LockKind::Posix, LockMode::Shared)
+        );
+        assert_eq!(entry.pid, Some(42));
+        assert_eq!(entry.file, FileId::new(libc::makedev(0xfd, 0x1a), 99));
+        assert_eq!((entry.start, entry.end), (100, Some(199)));
+    }
+
+    #[test]
+    fn parses_nested_waiting_lock() {
+        let entry = parse("3:  -> FLOCK  ADVISORY  READ 7 00:2e:12 0 EOF").unwrap();
+
+        assert!(entry.waiting);
+        assert_eq!((entry.kind, entry.pid), (LockKind::Flock, Some(7)));
+    }
+
+    #[test]
+    fn parses_ofd_lock_without_owner() {
+        let entry = parse("4: OFDLCK ADVISORY  WRITE -1 08:01:5678 0 EOF").unwrap();
+
+        assert_eq!((entry.kind, entry.pid), (LockKind::Ofd, None));
+    }
+
+    #[test]
+    fn skips_leases_and_malformed_lines() {
+        for line in [
+            "5: LEASE  ACTIVE    READ  1234 08:01:5678 0 EOF",
+            "6: DELEG  ACTIVE    READ  1234 08:01:5678 0 EOF",
+            "7: FLOCK  ADVISORY  WRITE 1234 08:01",
+            "FLOCK  ADVISORY  WRITE 1234 08:01:5678 0 EOF",
+            "",
+        ] {
+            assert!(parse(line).is_none(), "{:?}", line);
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use ::std::{
+    borrow::Borrow,
+    fs::File,
+    io::{self, prelude::*, SeekFrom},
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::Deref,
+    ptr,
+    time::Instant,
+};
+
+use crate::{
+    sys, Acquisition, DropPolicy, Exclusive, LockError, LockMode, LockOptions, LockState, Mode,
+    OfdFcntl, Shared,
+};
+
+/// Wrapper over a file with `len` bytes from `offset` on locked with Linux open file description
+/// locks, unlocking them at [dropping][`Drop`].
+///
+/// Like `flock` locks and unlike classic POSIX record locks, they belong to the open file
+/// description instead of the process, so they conflict with the ones taken through other
+/// descriptions of the same file in the same process and aren't released when closing other
+/// descriptors of the file. A `len` of 0 stands for the range from `offset` up to the end of the
+/// file however it grows.
+///
+/// Exclusive locks require the file to be open for writing and shared ones for reading. `M` is
+/// either [`Shared`] or [`Exclusive`], only the latter implementing [`Write`] and giving access to
+/// the file.
+#[derive(Debug)]
+pub struct RangeLock<F: Borrow<File>, M: Mode> {
+    file: F,
+    offset: u64,
+    len: u64,
+    state: LockState,
+    mode: PhantomData<M>,
+}
+
+impl<F: Borrow<File>, M: Mode> RangeLock<F, M> {
+    fn wrap(f: F, offset: u64, len: u64, wait: bool, restart: bool) -> io::Result<Self> {
+        let (cmd, acquisition) = if wait {
+            (libc::F_OFD_SETLKW, Acquisition::Blocking)
+        } else {
+            (libc::F_OFD_SETLK, Acquisition::Try)
+        };
+
+        loop {
+            match sys::setlk(f.borrow(), cmd, Some(M::MODE), offset, len) {
+                Err(e) if restart && e.kind() == io::ErrorKind::Interrupted => (),
+                r => break r?,
+            }
+        }
+
+        let mut state = LockState::new(&OfdFcntl, M::MODE, acquisition);
+        state.restart = restart;
+
+        Ok(Self {
+            file: f,
+            offset,
+            len,
+            state,
+            mode: PhantomData,
+        })
+    }
+
+    #[inline(always)]
+    fn file(&self) -> &File {
+        self.file.borrow()
+    }
+
+    fn release(&self) -> io::Result<()> {
+        sys::setlk(self.file(), libc::F_OFD_SETLK, None, self.offset, self.len)
+    }
+
+    /// Returns the offset the locked range starts at.
+    #[inline(always)]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the length of the locked range, 0 meaning up to the end of the file.
+    #[inline(always)]
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns the mode the range is locked in, which is always `M::MODE`.
+    #[inline(always)]
+    pub fn mode(&self) -> LockMode {
+        self.state.mode
+    }
+
+    /// Returns how the range was locked.
+    #[inline(always)]
+    pub fn acquisition(&self) -> Acquisition {
+        self.state.acquisition
+    }
+
+    /// Returns when the range was locked.
+    #[inline(always)]
+    pub fn acquired_at(&self) -> Instant {
+        self.state.acquired_at
+    }
+
+    /// Sets the policy followed if unlocking fails at [dropping][`Drop`], overriding the global
+    /// one.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.state.policy = Some(policy);
+    }
+
+    fn into_parts(self) -> (F, LockState) {
+        let this = ManuallyDrop::new(self);
+        // SAFETY: `this` is never dropped nor used again after moving its fields out.
+        unsafe { (ptr::read(&this.file), ptr::read(&this.state)) }
+    }
+
+    /// Unlocks the range the same way [dropping][`Drop`] does and gives the wrapped value back.
+    pub fn into_inner(self) -> F {
+        let r = self.release();
+        let (f, state) = self.into_parts();
+
+        if let Err(e) = r {
+            state.report(e, f.borrow());
+        }
+
+        f
+    }
+
+    /// Unlocks the range and gives the wrapped value back, returning it along with the error
+    /// instead of reporting it like [dropping][`Drop`] does if unlocking fails.
+    pub fn try_into_inner(self) -> Result<F, LockError<F>> {
+        let r = self.release();
+        let (f, _state) = self.into_parts();
+
+        match r {
+            Ok(()) => Ok(f),
+            Err(e) => Err(LockError::new(f, e)),
+        }
+    }
+
+    /// Unlocks the range returning any error that could have caused instead of reporting it
+    /// like [dropping][`Drop`] does.
+    pub fn unlock(self) -> io::Result<()> {
+        self.try_into_inner().map(drop).map_err(io::Error::from)
+    }
+}
+
+impl<F: Borrow<File>> RangeLock<F, Shared> {
+    /// Creates a `Self` instance locking the range shared with `F_OFD_SETLK` and returning any
+    /// error that could have caused.
+    pub fn try_wrap_shared(f: F, offset: u64, len: u64) -> io::Result<Self> {
+        Self::wrap(f, offset, len, false, true)
+    }
+
+    /// Creates a `Self` instance locking the range shared with `F_OFD_SETLKW`, again if
+    /// interrupted by a signal, and returning any error that could have caused.
+    pub fn wrap_shared(f: F, offset: u64, len: u64) -> io::Result<Self> {
+        Self::wrap(f, offset, len, true, true)
+    }
+
+    /// Creates a `Self` instance locking the range shared with `F_OFD_SETLKW`, again if
+    /// interrupted by a signal only if `options` [restart][`LockOptions::restart_interrupted`]
+    /// interrupted acquisitions, and returning any error that could have caused. The rest of the
+    /// settings don't apply to ranges.
+    pub fn wrap_shared_with(
+        f: F,
+        offset: u64,
+        len: u64,
+        options: &LockOptions,
+    ) -> io::Result<Self> {
+        Self::wrap(f, offset, len, true, options.restarts_interrupted())
+    }
+}
+
+impl<F: Borrow<File>> RangeLock<F, Exclusive> {
+    /// Creates a `Self` instance locking the range exclusive with `F_OFD_SETLK` and returning
+    /// any error that could have caused.
+    pub fn try_wrap_exclusive(f: F, offset: u64, len: u64) -> io::Result<Self> {
+        Self::wrap(f, offset, len, false, true)
+    }
+
+    /// Creates a `Self` instance locking the range exclusive with `F_OFD_SETLKW`, again if
+    /// interrupted by a signal, and returning any error that could have caused.
+    pub fn wrap_exclusive(f: F, offset: u64, len: u64) -> io::Result<Self> {
+        Self::wrap(f, offset, len, true, true)
+    }
+
+    /// Creates a `Self` instance locking the range exclusive with `F_OFD_SETLKW`, again if
+    /// interrupted by a signal only if `options` [restart][`LockOptions::restart_interrupted`]
+    /// interrupted acquisitions, and returning any error that could have caused. The rest of the
+    /// settings don't apply to ranges.
+    pub fn wrap_exclusive_with(
+        f: F,
+        offset: u64,
+        len: u64,
+        options: &LockOptions,
+    ) -> io::Result<Self> {
+        Self::wrap(f, offset, len, true, options.restarts_interrupted())
+    }
+
+    /// Returns a reference to the wrapped value.
+    #[inline(always)]
+    pub fn get_ref(&self) -> &F {
+        &self.file
+    }
+}
+
+impl<F: Borrow<File>> Write for RangeLock<F, Exclusive> {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file().write(buf)
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> io::Result<()> {
+        self.file().flush()
+    }
+}
+
+impl<F: Borrow<File>, M: Mode> Read for RangeLock<F, M> {
+    #[inline(always)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file().read(buf)
+    }
+}
+
+impl<F: Borrow<File>, M: Mode> Seek for RangeLock<F, M> {
+    #[inline(always)]
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file().seek(pos)
+    }
+}
+
+impl<F: Borrow<File>> Deref for RangeLock<F, Exclusive> {
+    type Target = File;
+
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        self.file()
+    }
+}
+
+impl<F: Borrow<File>, M: Mode> Drop for RangeLock<F, M> {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            self.state.report(e, self.file());
+        }
+    }
+}
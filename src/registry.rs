@@ -0,0 +1,417 @@
+use ::std::{
+    collections::BTreeMap,
+    fs::File,
+    io,
+    sync::{Arc, Condvar, Mutex, MutexGuard, RwLock},
+};
+
+#[cfg(target_os = "linux")]
+use ::std::time::Instant;
+
+use crate::{
+    acquire::{self, ConversionError, Retry, Wait},
+    file_id::FileId,
+    LockBackend, LockMode,
+};
+
+/// What locking a file the process already holds a lock on does when the modes conflict, in
+/// place of waiting for itself forever like `flock` would through another open file description.
+///
+/// Files are told apart by their device and inode, so it doesn't matter which path or handle
+/// they're locked through. While the registry is in use a single lock is taken on each file and
+/// shared by all the guards of the process, being released by the last one dropped. Guards
+/// sharing a lock with others can't be converted between modes.
+///
+/// The registry is opt-in: guards only use it if a policy was set globally with
+/// [`set_registry_policy`] or with [`LockOptions::registry`][`crate::LockOptions::registry`].
+/// Locks taken with the [`PosixFcntl`][`crate::PosixFcntl`] backend are still released as soon as
+/// any descriptor of the file is closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RegistryPolicy {
+    /// Fails with an error of kind [`io::ErrorKind::Deadlock`].
+    Error,
+    /// Waits until the guards of the process holding the file are dropped.
+    Wait,
+    /// Shares the lock of the process if it's exclusive, waiting like [`RegistryPolicy::Wait`]
+    /// otherwise.
+    Share,
+}
+
+/// Lock of the process on a file.
+struct Entry {
+    mode: LockMode,
+    holders: usize,
+    /// Duplicate of the handle the lock was taken through, keeping it alive as long as the entry.
+    file: Option<Arc<File>>,
+    /// Whether the lock is being taken or converted.
+    busy: bool,
+}
+
+static ENTRIES: Mutex<BTreeMap<FileId, Entry>> = Mutex::new(BTreeMap::new());
+static CHANGED: Condvar = Condvar::new();
+static POLICY: RwLock<Option<RegistryPolicy>> = RwLock::new(None);
+
+/// Sets the policy followed by guards that don't override it, `None` meaning they don't use the
+/// registry. This is the default.
+pub fn set_registry_policy(policy: Option<RegistryPolicy>) {
+    *POLICY.write().unwrap_or_else(|e| e.into_inner()) = policy;
+}
+
+/// Returns the policy followed by guards that don't override it.
+pub fn registry_policy() -> Option<RegistryPolicy> {
+    *POLICY.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn entries() -> MutexGuard<'static, BTreeMap<FileId, Entry>> {
+    ENTRIES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) fn held_by_process() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Deadlock,
+        "file already locked by this process",
+    )
+}
+
+/// Returns whether the process holds an exclusive lock on the file with `id` in the registry,
+/// which the [`RegistryPolicy::Share`] policy shares instead of waiting.
+pub(crate) fn held_exclusive(id: FileId) -> bool {
+    entries()
+        .get(&id)
+        .is_some_and(|entry| entry.mode == LockMode::Exclusive)
+}
+
+/// Waits for the entries to change as `wait` says, counting the wait as a failed attempt of
+/// `retry` if polling.
+fn wait_changed<'a>(
+    entries: MutexGuard<'a, BTreeMap<FileId, Entry>>,
+    wait: &Wait,
+    retry: Option<&mut Retry<'_>>,
+) -> io::Result<MutexGuard<'a, BTreeMap<FileId, Entry>>> {
+    let timeout = match (wait, retry) {
+        (Wait::No, _) => return Err(io::ErrorKind::WouldBlock.into()),
+        (_, Some(retry)) => Some(retry.failed(io::ErrorKind::WouldBlock.into())?),
+        #[cfg(target_os = "linux")]
+        (Wait::Alarm(deadline, ..), None) => {
+            let now = Instant::now();
+
+            if now >= *deadline {
+                return Err(acquire::timed_out());
+            }
+
+            Some(*deadline - now)
+        }
+        _ => None,
+    };
+
+    let entries = match timeout {
+        Some(timeout) => match CHANGED.wait_timeout(entries, timeout) {
+            Ok((entries, _)) => entries,
+            Err(e) => e.into_inner().0,
+        },
+        None => CHANGED.wait(entries).unwrap_or_else(|e| e.into_inner()),
+    };
+
+    Ok(entries)
+}
+
+/// Locks `f` with `backend` in `mode` through the registry following `policy`, waiting as `wait`
+/// says both for the guards of the process and for the lock itself.
+pub(crate) fn lock(
+    policy: RegistryPolicy,
+    backend: &dyn LockBackend,
+    f: &File,
+    mode: LockMode,
+    wait: &Wait,
+) -> io::Result<Registration> {
+    let id = FileId::of(f)?;
+    // A single series of attempts for both the guards of the process and the lock itself.
+    let mut retry = match wait {
+        Wait::Retry(policy, deadline) => Some(Retry::new(policy, *deadline)),
+        _ => None,
+    };
+    let mut entries = entries();
+
+    loop {
+        match entries.get_mut(&id) {
+            None => break,
+            Some(entry) if entry.busy => (),
+            Some(entry)
+                if entry.mode == LockMode::Shared && mode == LockMode::Shared
+                    || entry.mode == LockMode::Exclusive && policy == RegistryPolicy::Share =>
+            {
+                entry.holders += 1;
+                return Ok(Registration { id });
+            }
+            Some(_) if policy == RegistryPolicy::Error => return Err(held_by_process()),
+            Some(_) => (),
+        }
+
+        entries = wait_changed(entries, wait, retry.as_mut())?;
+    }
+
+    entries.insert(
+        id,
+        Entry {
+            mode,
+            holders: 1,
+            file: None,
+            busy: true,
+        },
+    );
+    drop(entries);
+
+    let r = match &mut retry {
+        Some(retry) => acquire::lock_retry(backend, f, mode, retry),
+        None => acquire::lock(backend, f, mode, wait),
+    };
+    let r = r.and_then(|()| {
+        f.try_clone().inspect_err(|_| {
+            let _ = backend.unlock(f);
+        })
+    });
+    let mut entries = self::entries();
+
+    let r = match r {
+        Ok(file) => {
+            if let Some(entry) = entries.get_mut(&id) {
+                entry.file = Some(Arc::new(file));
+                entry.busy = false;
+            }
+
+            Ok(Registration { id })
+        }
+        Err(e) => {
+            entries.remove(&id);
+            Err(e)
+        }
+    };
+
+    CHANGED.notify_all();
+    r
+}
+
+/// Share of a guard in the lock of the process on a file.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    id: FileId,
+}
+
+impl Registration {
+    /// Gives the share up, unlocking the file with `backend` if it was the last one.
+    pub(crate) fn release(&self, backend: &dyn LockBackend) -> io::Result<()> {
+        let mut entries = entries();
+
+        match entries.get_mut(&self.id) {
+            Some(entry) if entry.holders > 1 => {
+                entry.holders -= 1;
+                return Ok(());
+            }
+            Some(_) => (),
+            None => return Ok(()),
+        }
+
+        let file = entries.remove(&self.id).and_then(|entry| entry.file);
+        drop(entries);
+        CHANGED.notify_all();
+
+        match file {
+            Some(file) => backend.unlock(&file),
+            None => Ok(()),
+        }
+    }
+
+    /// Converts the lock like [`acquire::convert`] does as long as no other guard shares it.
+    pub(crate) fn convert(
+        &self,
+        backend: &dyn LockBackend,
+        from: LockMode,
+        to: LockMode,
+        wait: bool,
+        restart: bool,
+    ) -> Result<bool, ConversionError> {
+        let mut entries = entries();
+        let file = match entries.get_mut(&self.id) {
+            Some(entry) if entry.holders > 1 => {
+                return Err(ConversionError::kept(held_by_process()))
+            }
+            Some(entry) => {
+                entry.busy = true;
+                entry.file.clone()
+            }
+            None => None,
+        };
+        drop(entries);
+
+        let r = match &file {
+            Some(file) => acquire::convert(backend, file, from, to, wait, restart),
+            None => Err(ConversionError::kept(io::Error::new(
+                io::ErrorKind::NotFound,
+                "lock no longer registered",
+            ))),
+        };
+        let mut entries = self::entries();
+
+        if let Some(entry) = entries.get_mut(&self.id) {
+            entry.busy = false;
+
+            if r.is_ok() {
+                entry.mode = to;
+            }
+        }
+
+        CHANGED.notify_all();
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{open_lock_file, Flock, RetryPolicy};
+    use std::{env, fs, path::PathBuf, process, time::Duration};
+    use LockMode::{Exclusive, Shared};
+
+    /// File removed at dropping, each test using its own so they don't share entries.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let name = format!("raii_flock-registry-{}-{}", name, process::id());
+            Self(env::temp_dir().join(name))
+        }
+
+        fn open(&self) -> File {
+            open_lock_file(&self.0, 0o600).unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn holders(f: &File) -> Option<usize> {
+        entries()
+            .get(&FileId::of(f).unwrap())
+            .map(|entry| entry.holders)
+    }
+
+    fn is_locked(file: &TempFile) -> bool {
+        let f = file.open();
+        let locked = Flock.try_lock(&f, Exclusive).is_err();
+
+        if !locked {
+            Flock.unlock(&f).unwrap();
+        }
+
+        locked
+    }
+
+    #[test]
+    fn error_policy_refuses_second_handle() {
+        let file = TempFile::new("error");
+        let (a, b) = (file.open(), file.open());
+        let held = lock(
+            RegistryPolicy::Error,
+            &Flock,
+            &a,
+            Exclusive,
+            &Wait::Blocking,
+        )
+        .unwrap();
+
+        let e = lock(RegistryPolicy::Error, &Flock, &b, Shared, &Wait::Blocking).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Deadlock);
+        held.release(&Flock).unwrap();
+    }
+
+    #[test]
+    fn share_policy_shares_exclusive_lock() {
+        let file = TempFile::new("share");
+        let (a, b) = (file.open(), file.open());
+        let first = lock(RegistryPolicy::Share, &Flock, &a, Exclusive, &Wait::No).unwrap();
+        let second = lock(RegistryPolicy::Share, &Flock, &b, Exclusive, &Wait::No).unwrap();
+
+        assert_eq!(holders(&a), Some(2));
+        second.release(&Flock).unwrap();
+        first.release(&Flock).unwrap();
+        assert_eq!(holders(&a), None);
+    }
+
+    #[test]
+    fn shared_locks_are_shared_under_every_policy() {
+        let file = TempFile::new("shared");
+        let (a, b) = (file.open(), file.open());
+        let first = lock(RegistryPolicy::Error, &Flock, &a, Shared, &Wait::No).unwrap();
+        let second = lock(RegistryPolicy::Error, &Flock, &b, Shared, &Wait::No).unwrap();
+
+        assert_eq!(holders(&a), Some(2));
+        second.release(&Flock).unwrap();
+        first.release(&Flock).unwrap();
+    }
+
+    #[test]
+    fn wait_policy_without_waiting_would_block() {
+        let file = TempFile::new("would-block");
+        let (a, b) = (file.open(), file.open());
+        let held = lock(RegistryPolicy::Wait, &Flock, &a, Shared, &Wait::No).unwrap();
+
+        let e = lock(RegistryPolicy::Wait, &Flock, &b, Exclusive, &Wait::No).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        held.release(&Flock).unwrap();
+    }
+
+    #[test]
+    fn wait_policy_gives_up_after_attempts() {
+        let file = TempFile::new("attempts");
+        let (a, b) = (file.open(), file.open());
+        let held = lock(RegistryPolicy::Wait, &Flock, &a, Exclusive, &Wait::No).unwrap();
+        let policy = RetryPolicy::fixed(Duration::from_millis(1)).max_attempts(3);
+
+        let e = lock(
+            RegistryPolicy::Wait,
+            &Flock,
+            &b,
+            Exclusive,
+            &Wait::Retry(policy, None),
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        held.release(&Flock).unwrap();
+    }
+
+    #[test]
+    fn last_holder_releases_the_lock() {
+        let file = TempFile::new("last");
+        let (a, b) = (file.open(), file.open());
+        let first = lock(RegistryPolicy::Share, &Flock, &a, Exclusive, &Wait::No).unwrap();
+        let second = lock(RegistryPolicy::Share, &Flock, &b, Exclusive, &Wait::No).unwrap();
+
+        first.release(&Flock).unwrap();
+        assert!(is_locked(&file));
+        second.release(&Flock).unwrap();
+        assert!(!is_locked(&file));
+    }
+
+    #[test]
+    fn shared_lock_isnt_converted() {
+        let file = TempFile::new("convert");
+        let (a, b) = (file.open(), file.open());
+        let first = lock(RegistryPolicy::Wait, &Flock, &a, Shared, &Wait::No).unwrap();
+        let second = lock(RegistryPolicy::Wait, &Flock, &b, Shared, &Wait::No).unwrap();
+
+        let e = first
+            .convert(&Flock, Shared, Exclusive, false, true)
+            .unwrap_err();
+        assert_eq!(e.error.kind(), io::ErrorKind::Deadlock);
+        assert!(!e.lost);
+
+        second.release(&Flock).unwrap();
+        assert!(first
+            .convert(&Flock, Shared, Exclusive, false, true)
+            .is_ok());
+        first.release(&Flock).unwrap();
+    }
+}
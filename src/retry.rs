@@ -0,0 +1,169 @@
+use ::std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// Schedule followed by polling acquisitions between two failed non-blocking attempts.
+///
+/// The default policy starts waiting 1ms, doubles the wait after every attempt up to 50ms and
+/// never gives up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    initial: Duration,
+    max_interval: Duration,
+    multiplier: u32,
+    jitter: bool,
+    max_attempts: Option<u32>,
+    max_elapsed: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// Creates a policy waiting `interval` between every two attempts.
+    pub fn fixed(interval: Duration) -> Self {
+        Self {
+            initial: interval,
+            max_interval: interval,
+            multiplier: 1,
+            jitter: false,
+            max_attempts: None,
+            max_elapsed: None,
+        }
+    }
+
+    /// Creates a policy waiting `initial` after the first attempt and doubling the wait after
+    /// every following one up to `max_interval`.
+    pub fn exponential(initial: Duration, max_interval: Duration) -> Self {
+        Self {
+            initial,
+            max_interval: max_interval.max(initial),
+            multiplier: 2,
+            jitter: false,
+            max_attempts: None,
+            max_elapsed: None,
+        }
+    }
+
+    /// Sets whether each wait is randomly shortened by up to half its length, spreading the
+    /// attempts of processes contending for the same file.
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Gives up after `attempts` failed attempts, returning the error the last one failed with.
+    ///
+    /// At least one attempt is always made.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts.max(1));
+        self
+    }
+
+    /// Gives up once `elapsed` has passed since the first attempt, returning an error of kind
+    /// [`TimedOut`][`std::io::ErrorKind::TimedOut`].
+    pub fn max_elapsed(mut self, elapsed: Duration) -> Self {
+        self.max_elapsed = Some(elapsed);
+        self
+    }
+
+    pub(crate) fn attempts(&self) -> Option<u32> {
+        self.max_attempts
+    }
+
+    pub(crate) fn elapsed(&self) -> Option<Duration> {
+        self.max_elapsed
+    }
+
+    pub(crate) fn backoff(&self) -> Backoff<'_> {
+        Backoff {
+            policy: self,
+            interval: self.initial,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::exponential(Duration::from_millis(1), Duration::from_millis(50))
+    }
+}
+
+/// State of the waits of a single acquisition following a [`RetryPolicy`].
+pub(crate) struct Backoff<'a> {
+    policy: &'a RetryPolicy,
+    interval: Duration,
+}
+
+impl Backoff<'_> {
+    /// Returns how long to wait before the next attempt.
+    pub(crate) fn next_wait(&mut self) -> Duration {
+        let wait = self.interval;
+        self.interval = wait
+            .checked_mul(self.policy.multiplier)
+            .map_or(self.policy.max_interval, |next| {
+                next.min(self.policy.max_interval)
+            });
+
+        if self.policy.jitter {
+            let nanos = (wait / 2).as_nanos() as u64;
+
+            if nanos != 0 {
+                let random = RandomState::new().build_hasher().finish();
+                return wait - Duration::from_nanos(random % nanos);
+            }
+        }
+
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waits(policy: &RetryPolicy, n: usize) -> Vec<Duration> {
+        let mut backoff = policy.backoff();
+        (0..n).map(|_| backoff.next_wait()).collect()
+    }
+
+    #[test]
+    fn fixed_waits_the_same() {
+        let interval = Duration::from_millis(10);
+        assert_eq!(waits(&RetryPolicy::fixed(interval), 4), [interval; 4]);
+    }
+
+    #[test]
+    fn exponential_doubles_up_to_the_cap() {
+        let policy = RetryPolicy::exponential(Duration::from_millis(1), Duration::from_millis(5));
+        let expected = [1, 2, 4, 5, 5].map(Duration::from_millis);
+
+        assert_eq!(waits(&policy, 5), expected);
+    }
+
+    #[test]
+    fn exponential_caps_instead_of_overflowing() {
+        let initial = Duration::from_secs(u64::MAX / 2 + 1);
+        let policy = RetryPolicy::exponential(initial, Duration::MAX);
+
+        assert_eq!(waits(&policy, 3), [initial, Duration::MAX, Duration::MAX]);
+    }
+
+    #[test]
+    fn jitter_shortens_by_up_to_half() {
+        let policy = RetryPolicy::exponential(Duration::from_millis(8), Duration::from_millis(64))
+            .jitter(true);
+        let expected = [8, 16, 32, 64, 64].map(Duration::from_millis);
+
+        for _ in 0..100 {
+            for (wait, full) in waits(&policy, 5).into_iter().zip(expected) {
+                assert!(wait <= full && wait > full / 2, "{:?} of {:?}", wait, full);
+            }
+        }
+    }
+
+    #[test]
+    fn jitter_keeps_tiny_waits() {
+        let policy = RetryPolicy::fixed(Duration::from_nanos(1)).jitter(true);
+        assert_eq!(waits(&policy, 2), [Duration::from_nanos(1); 2]);
+    }
+}
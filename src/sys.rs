@@ -0,0 +1,215 @@
+use ::std::{convert::TryFrom, fs::File, io, mem, os::unix::io::AsRawFd};
+
+#[cfg(target_os = "linux")]
+use ::std::{
+    ptr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::LockMode;
+
+/// Builds the `flock` structure describing `len` bytes of `f` from `start` on, up to the end of
+/// the file if `len` is 0, to be locked in `mode` or unlocked if it's `None`.
+fn flock(mode: Option<LockMode>, start: u64, len: u64) -> io::Result<libc::flock> {
+    let invalid = |_| io::Error::new(io::ErrorKind::InvalidInput, "lock range out of bounds");
+
+    // SAFETY: `flock` is a plain C structure for which all zeroes is a valid value.
+    let mut lock: libc::flock = unsafe { mem::zeroed() };
+    lock.l_type = match mode {
+        Some(LockMode::Shared) => libc::F_RDLCK,
+        Some(LockMode::Exclusive) => libc::F_WRLCK,
+        None => libc::F_UNLCK,
+    } as _;
+    lock.l_whence = libc::SEEK_SET as _;
+    lock.l_start = libc::off_t::try_from(start).map_err(invalid)?;
+    lock.l_len = libc::off_t::try_from(len).map_err(invalid)?;
+
+    Ok(lock)
+}
+
+/// Calls `fcntl` on `f` with the locking command `cmd` for the range described by the other
+/// arguments as in [`flock`], reporting an `EACCES` returned by a non-blocking command as
+/// `EWOULDBLOCK`.
+pub(crate) fn setlk(
+    f: &File,
+    cmd: libc::c_int,
+    mode: Option<LockMode>,
+    start: u64,
+    len: u64,
+) -> io::Result<()> {
+    let lock = flock(mode, start, len)?;
+
+    // SAFETY: `lock` is a valid `flock` structure living through the call.
+    if unsafe { libc::fcntl(f.as_raw_fd(), cmd, &lock) } == -1 {
+        let e = io::Error::last_os_error();
+
+        return Err(match e.raw_os_error() {
+            Some(libc::EACCES) if non_blocking(cmd) => {
+                io::Error::from_raw_os_error(libc::EWOULDBLOCK)
+            }
+            _ => e,
+        });
+    }
+
+    Ok(())
+}
+
+/// Returns whether `cmd` is a locking command failing instead of waiting on conflicts.
+fn non_blocking(cmd: libc::c_int) -> bool {
+    #[cfg(target_os = "linux")]
+    if cmd == libc::F_OFD_SETLK {
+        return true;
+    }
+
+    cmd == libc::F_SETLK
+}
+
+/// Calls `fcntl` on `f` with the lock testing command `cmd` for the range described by the other
+/// arguments as in [`flock`], returning the first lock that would conflict with taking it in
+/// `mode` if any.
+#[cfg(target_os = "linux")]
+pub(crate) fn getlk(
+    f: &File,
+    cmd: libc::c_int,
+    mode: LockMode,
+    start: u64,
+    len: u64,
+) -> io::Result<Option<libc::flock>> {
+    let mut lock = flock(Some(mode), start, len)?;
+
+    // SAFETY: `lock` is a valid `flock` structure living through the call.
+    if unsafe { libc::fcntl(f.as_raw_fd(), cmd, &mut lock) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(if lock.l_type == libc::F_UNLCK as _ {
+        None
+    } else {
+        Some(lock)
+    })
+}
+
+/// Signals whose handler was replaced by [`interrupt`].
+#[cfg(target_os = "linux")]
+static HANDLED: Mutex<Vec<libc::c_int>> = Mutex::new(Vec::new());
+
+/// How often an [`Alarm`] sends its signal again after the first time.
+#[cfg(target_os = "linux")]
+const REPEAT: Duration = Duration::from_millis(10);
+
+#[cfg(target_os = "linux")]
+extern "C" fn interrupt(_: libc::c_int) {}
+
+#[cfg(target_os = "linux")]
+fn timespec(d: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: d.as_secs() as libc::time_t,
+        tv_nsec: d.subsec_nanos() as _,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sigset(signal: libc::c_int) -> libc::sigset_t {
+    // SAFETY: `sigset_t` is a plain C structure initialized by `sigemptyset`.
+    unsafe {
+        let mut set = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, signal);
+        set
+    }
+}
+
+/// Timer interrupting the blocking calls of the thread that created it by sending it a signal
+/// once a deadline is reached and every [`REPEAT`] after, in case it came before the call, until
+/// dropped.
+#[cfg(target_os = "linux")]
+pub(crate) struct Alarm {
+    timer: libc::timer_t,
+    signal: libc::c_int,
+    mask: libc::sigset_t,
+}
+
+#[cfg(target_os = "linux")]
+impl Alarm {
+    /// Arms a timer sending `signal` to the current thread at `deadline`, replacing the handler
+    /// of the signal by one doing nothing and not restarting calls if not done yet.
+    pub(crate) fn new(signal: libc::c_int, deadline: Instant) -> io::Result<Self> {
+        let mut handled = HANDLED.lock().unwrap_or_else(|e| e.into_inner());
+
+        if !handled.contains(&signal) {
+            // SAFETY: `sigaction` is a plain C structure for which all zeroes is a valid value,
+            // meaning no flags and an empty mask.
+            let mut action: libc::sigaction = unsafe { mem::zeroed() };
+            action.sa_sigaction = interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+
+            // SAFETY: `action` is a valid `sigaction` structure living through the call.
+            if unsafe { libc::sigaction(signal, &action, ptr::null_mut()) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            handled.push(signal);
+        }
+
+        drop(handled);
+
+        // SAFETY: `sigevent` is a plain C structure for which all zeroes is a valid value.
+        let mut event: libc::sigevent = unsafe { mem::zeroed() };
+        event.sigev_notify = libc::SIGEV_THREAD_ID;
+        event.sigev_signo = signal;
+        // SAFETY: `gettid` has no preconditions.
+        event.sigev_notify_thread_id = unsafe { libc::gettid() };
+        let mut timer = ptr::null_mut();
+
+        // SAFETY: `event` and `timer` are valid for the call to read and write respectively.
+        if unsafe { libc::timer_create(libc::CLOCK_MONOTONIC, &mut event, &mut timer) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: `sigset_t` is a plain C structure for which all zeroes is a valid value.
+        let mut alarm = Self {
+            timer,
+            signal,
+            mask: unsafe { mem::zeroed() },
+        };
+
+        // The signal could be blocked in the thread, which would keep it from interrupting.
+        // SAFETY: both sets are valid for the call.
+        unsafe { libc::pthread_sigmask(libc::SIG_UNBLOCK, &sigset(signal), &mut alarm.mask) };
+
+        let spec = libc::itimerspec {
+            it_interval: timespec(REPEAT),
+            // A zero value would disarm the timer.
+            it_value: timespec(
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .max(Duration::from_nanos(1)),
+            ),
+        };
+
+        // SAFETY: `timer` was created above and `spec` is valid for the call.
+        if unsafe { libc::timer_settime(timer, 0, &spec, ptr::null_mut()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(alarm)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Alarm {
+    fn drop(&mut self) {
+        let set = sigset(self.signal);
+        let zero = timespec(Duration::ZERO);
+
+        // Blocked while deleting the timer so a signal sent in between is left pending and then
+        // discarded instead of interrupting whatever the thread does next.
+        // SAFETY: `set` and `mask` are valid for the calls and `timer` is only deleted here.
+        unsafe {
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+            libc::timer_delete(self.timer);
+            while libc::sigtimedwait(&set, ptr::null_mut(), &zero) == self.signal {}
+            libc::pthread_sigmask(libc::SIG_SETMASK, &self.mask, ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use ::std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    file_id::FileId,
+    proc_locks::{self, Entry},
+    LockKind, LockMode,
+};
+
+/// Process waiting for a lock on a file held by another one, as listed in `/proc/locks`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockWait {
+    waiter: u32,
+    holder: u32,
+    mode: LockMode,
+    kind: LockKind,
+    file: FileId,
+    path: Option<PathBuf>,
+}
+
+impl LockWait {
+    /// Returns the process waiting for the lock.
+    #[inline(always)]
+    pub fn waiter(&self) -> u32 {
+        self.waiter
+    }
+
+    /// Returns the process holding a lock on the file that conflicts with the one waited for,
+    /// which can be the waiter itself for `flock` locks taken through other open file
+    /// descriptions, by the same thread or by another one the graph can't tell apart.
+    #[inline(always)]
+    pub fn holder(&self) -> u32 {
+        self.holder
+    }
+
+    /// Returns the mode the waiter waits to lock the file in.
+    #[inline(always)]
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Returns the kind of the lock waited for.
+    #[inline(always)]
+    pub fn kind(&self) -> LockKind {
+        self.kind
+    }
+
+    /// Returns the device number of the file.
+    #[inline(always)]
+    pub fn device(&self) -> u64 {
+        self.file.dev()
+    }
+
+    /// Returns the inode number of the file.
+    #[inline(always)]
+    pub fn inode(&self) -> u64 {
+        self.file.ino()
+    }
+
+    /// Returns a path of the file, if one could be found among the descriptors the waiter and the
+    /// holder have open in `/proc`.
+    #[inline(always)]
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
+impl fmt::Display for LockWait {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "process {} waits to lock ", self.waiter)?;
+
+        match &self.path {
+            Some(path) => write!(f, "{}", path.display())?,
+            None => write!(f, "file {}:{}", self.device(), self.inode())?,
+        }
+
+        let mode = match self.mode {
+            LockMode::Shared => "shared",
+            LockMode::Exclusive => "exclusive",
+        };
+
+        write!(f, " {}, held by process {}", mode, self.holder)
+    }
+}
+
+/// Cycle of processes each waiting for a lock held by the next one, the last one waiting for the
+/// first one, which none of them will ever get.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProcessDeadlock {
+    waits: Vec<LockWait>,
+}
+
+impl ProcessDeadlock {
+    /// Returns the waits of the cycle, starting with the process with the lowest PID.
+    #[inline(always)]
+    pub fn waits(&self) -> &[LockWait] {
+        &self.waits
+    }
+
+    /// Returns whether `pid` is one of the processes of the cycle.
+    pub fn involves(&self, pid: u32) -> bool {
+        self.waits.iter().any(|wait| wait.waiter == pid)
+    }
+}
+
+impl fmt::Display for ProcessDeadlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("file lock deadlock: ")?;
+
+        for (i, wait) in self.waits.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+
+            write!(f, "{}", wait)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// System-wide graph of the processes waiting for file locks held by others, built from
+/// `/proc/locks`.
+///
+/// Each waiter listed with `->` waits for every lock of the same family on its file held in a
+/// conflicting mode by another owner over an overlapping range, `flock` locks only conflicting
+/// with each other and POSIX record locks with open file description ones. Open file description
+/// locks are left out of the graph as the kernel doesn't report who holds them.
+///
+/// The graph is made of processes, as `/proc/locks` doesn't tell their threads apart, so a
+/// process waiting for a lock it holds itself may only be waiting for another of its threads to
+/// release it. Such waits are listed but never part of a cycle, see
+/// [`DeadlockDetection`][`crate::DeadlockDetection`] for the ones within a process.
+#[derive(Clone, Debug, Default)]
+pub struct WaitGraph {
+    waits: Vec<LockWait>,
+}
+
+impl WaitGraph {
+    /// Reads `/proc/locks` and builds the graph, looking for the paths of the files involved
+    /// among the descriptors open in `/proc/<pid>/fd`.
+    pub fn read() -> io::Result<Self> {
+        let entries = proc_locks::read()?;
+        let mut paths = Paths::default();
+        let mut waits = Vec::new();
+
+        for waiting in entries.iter().filter(|entry| entry.waiting) {
+            let waiter = match waiting.pid {
+                Some(pid) => pid,
+                None => continue,
+            };
+
+            for held in entries.iter().filter(|entry| blocks(entry, waiting)) {
+                let holder = match held.pid {
+                    Some(pid) => pid,
+                    None => continue,
+                };
+
+                if waits
+                    .iter()
+                    .any(|wait: &LockWait| wait.waiter == waiter && wait.holder == holder)
+                {
+                    continue;
+                }
+
+                waits.push(LockWait {
+                    waiter,
+                    holder,
+                    mode: waiting.mode,
+                    kind: waiting.kind,
+                    file: waiting.file,
+                    path: paths.find(waiting.file, &[waiter, holder]),
+                });
+            }
+        }
+
+        Ok(Self { waits })
+    }
+
+    /// Returns every wait of the graph, one per pair of waiter and holder.
+    #[inline(always)]
+    pub fn waits(&self) -> &[LockWait] {
+        &self.waits
+    }
+
+    /// Returns every cycle of the graph through at least two processes.
+    pub fn cycles(&self) -> Vec<ProcessDeadlock> {
+        let mut pids: Vec<u32> = self.waits.iter().map(|wait| wait.waiter).collect();
+        pids.sort_unstable();
+        pids.dedup();
+
+        let mut cycles = Vec::new();
+
+        // Looking for the cycles through each process that don't go through lower PIDs, so each
+        // is found once starting from its lowest PID.
+        for &start in &pids {
+            let mut path = Vec::new();
+            self.find_cycles(start, start, &mut path, &mut cycles);
+        }
+
+        cycles
+    }
+
+    fn find_cycles<'a>(
+        &'a self,
+        start: u32,
+        pid: u32,
+        path: &mut Vec<&'a LockWait>,
+        cycles: &mut Vec<ProcessDeadlock>,
+    ) {
+        // Waits of a process for itself are left out, as they may be for another of its threads.
+        for wait in self
+            .waits
+            .iter()
+            .filter(|wait| wait.waiter == pid && wait.holder != pid)
+        {
+            if wait.holder == start {
+                let mut waits: Vec<LockWait> = path.iter().map(|&wait| wait.clone()).collect();
+                waits.push(wait.clone());
+                cycles.push(ProcessDeadlock { waits });
+            } else if wait.holder > start && path.iter().all(|w| w.waiter != wait.holder) {
+                path.push(wait);
+                self.find_cycles(start, wait.holder, path, cycles);
+                path.pop();
+            }
+        }
+    }
+}
+
+/// Returns whether `held` keeps `waiting` from being granted.
+fn blocks(held: &Entry, waiting: &Entry) -> bool {
+    if held.waiting || held.file != waiting.file {
+        return false;
+    }
+
+    if held.mode == LockMode::Shared && waiting.mode == LockMode::Shared
+        || held.end.is_some_and(|end| end < waiting.start)
+        || waiting.end.is_some_and(|end| end < held.start)
+    {
+        return false;
+    }
+
+    match (held.kind, waiting.kind) {
+        (LockKind::Flock, LockKind::Flock) => true,
+        // Record locks of a process never conflict with each other.
+        (LockKind::Posix, LockKind::Posix) => held.pid != waiting.pid,
+        (LockKind::Flock, _) | (_, LockKind::Flock) => false,
+        _ => true,
+    }
+}
+
+/// Paths of the files open by processes, looked up in `/proc/<pid>/fd` as needed.
+#[derive(Default)]
+struct Paths {
+    open: BTreeMap<u32, Vec<(FileId, PathBuf)>>,
+}
+
+impl Paths {
+    fn find(&mut self, file: FileId, pids: &[u32]) -> Option<PathBuf> {
+        pids.iter().find_map(|&pid| {
+            self.open
+                .entry(pid)
+                .or_insert_with(|| open_files(pid))
+                .iter()
+                .find(|(id, _)| *id == file)
+                .map(|(_, path)| path.clone())
+        })
+    }
+}
+
+/// Lists the files `pid` has open, empty if its descriptors can't be read.
+fn open_files(pid: u32) -> Vec<(FileId, PathBuf)> {
+    let fds = match fs::read_dir(format!("/proc/{}/fd", pid)) {
+        Ok(fds) => fds,
+        Err(_) => return Vec::new(),
+    };
+
+    fds.filter_map(|fd| {
+        let fd = fd.ok()?.path();
+        let id = FileId::of_path(&fd).ok()?;
+        Some((id, fs::read_link(&fd).ok()?))
+    })
+    .collect()
+}
+
+/// Returns the cycles of the system-wide [`WaitGraph`] the current process is part of, holding a
+/// lock on a file another process of the cycle waits for.
+pub fn process_deadlocks() -> io::Result<Vec<ProcessDeadlock>> {
+    let pid = process::id();
+
+    Ok(WaitGraph::read()?
+        .cycles()
+        .into_iter()
+        .filter(|cycle| cycle.involves(pid))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait(waiter: u32, holder: u32) -> LockWait {
+        LockWait {
+            waiter,
+            holder,
+            mode: LockMode::Exclusive,
+            kind: LockKind::Flock,
+            file: FileId::new(1, u64::from(holder)),
+            path: None,
+        }
+    }
+
+    fn cycles(waits: &[(u32, u32)]) -> Vec<Vec<(u32, u32)>> {
+        let graph = WaitGraph {
+            waits: waits
+                .iter()
+                .map(|&(waiter, holder)| wait(waiter, holder))
+                .collect(),
+        };
+
+        graph
+            .cycles()
+            .iter()
+            .map(|cycle| cycle.waits().iter().map(|w| (w.waiter, w.holder)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn chain_is_no_cycle() {
+        assert!(cycles(&[(1, 2), (2, 3)]).is_empty());
+    }
+
+    #[test]
+    fn finds_each_cycle_once_from_lowest_pid() {
+        assert_eq!(cycles(&[(20, 10), (10, 20)]), [vec![(10, 20), (20, 10)]]);
+        assert_eq!(
+            cycles(&[(3, 1), (2, 3), (1, 2), (4, 1)]),
+            [vec![(1, 2), (2, 3), (3, 1)]]
+        );
+    }
+
+    #[test]
+    fn finds_cycles_sharing_a_process() {
+        assert_eq!(
+            cycles(&[(1, 2), (2, 1), (2, 3), (3, 2)]),
+            [vec![(1, 2), (2, 1)], vec![(2, 3), (3, 2)]]
+        );
+    }
+
+    #[test]
+    fn leaves_waits_for_itself_out() {
+        assert!(cycles(&[(1, 1)]).is_empty());
+        assert_eq!(cycles(&[(1, 1), (1, 2), (2, 1)]), [vec![(1, 2), (2, 1)]]);
+    }
+
+    #[test]
+    fn involves_only_waiters_of_the_cycle() {
+        let graph = WaitGraph {
+            waits: vec![wait(1, 2), wait(2, 1), wait(3, 1)],
+        };
+        let cycles = graph.cycles();
+
+        assert_eq!(cycles.len(), 1);
+        assert!(cycles[0].involves(1) && cycles[0].involves(2) && !cycles[0].involves(3));
+    }
+}